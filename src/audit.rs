@@ -0,0 +1,99 @@
+//! Append-only audit trail of tool calls (NU_AUDIT_LOG)
+
+use crate::exec::redact_secrets;
+use regex::Regex;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex as TokioMutex;
+
+/// Arguments worth keeping in the audit trail; bulky or sensitive ones (code_edit, env values,
+/// headers, request bodies) are left out
+const AUDITED_ARGS: &[&str] = &[
+    "command", "script", "script_path", "args", "cwd", "session", "background", "path", "instructions",
+    "url", "method", "query", "id", "op", "name", "signal", "token",
+    "pattern", "glob",
+];
+
+/// One JSON line in the audit log
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    pub timestamp: String,
+    pub tool: String,
+    pub args: Map<String, Value>,
+    /// "ok", "tool_error" (a result flagged `is_error`) or "error" (an MCP error)
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_ms: u128,
+}
+
+#[derive(Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+    /// Server-default secret patterns plus the literal APPLY_API_KEY
+    redactions: Vec<Regex>,
+    /// Serializes appends so concurrent calls never interleave lines
+    lock: Arc<TokioMutex<()>>,
+}
+
+impl AuditLog {
+    /// Audit log configured by NU_AUDIT_LOG, if any
+    pub fn from_env(mut redactions: Vec<Regex>) -> Option<Self> {
+        let path = std::env::var("NU_AUDIT_LOG").ok().filter(|p| !p.is_empty())?;
+        if let Some(key) = std::env::var("APPLY_API_KEY").ok().filter(|k| k.len() >= 8) {
+            redactions.extend(Regex::new(&regex::escape(&key)).ok());
+        }
+        Some(Self {
+            path: PathBuf::from(path),
+            redactions,
+            lock: Arc::new(TokioMutex::new(())),
+        })
+    }
+
+    /// Keep the audited subset of a call's arguments, with secrets scrubbed from string values
+    pub fn select_args(&self, arguments: Option<&Map<String, Value>>) -> Map<String, Value> {
+        let Some(arguments) = arguments else {
+            return Map::new();
+        };
+        AUDITED_ARGS
+            .iter()
+            .filter_map(|key| arguments.get(*key).map(|value| (key.to_string(), self.redact_value(value))))
+            .collect()
+    }
+
+    fn redact_value(&self, value: &Value) -> Value {
+        match value {
+            Value::String(s) => Value::String(redact_secrets(s, &self.redactions)),
+            Value::Array(items) => Value::Array(items.iter().map(|v| self.redact_value(v)).collect()),
+            other => other.clone(),
+        }
+    }
+
+    /// Append an entry; failures are logged but never fail the tool call
+    pub async fn record(&self, mut entry: AuditEntry) {
+        entry.error = entry.error.map(|e| redact_secrets(&e, &self.redactions));
+        let mut line = match serde_json::to_string(&entry) {
+            Ok(line) => line,
+            Err(e) => {
+                tracing::warn!("Failed to serialize audit entry: {}", e);
+                return;
+            }
+        };
+        line.push('\n');
+
+        let _guard = self.lock.lock().await;
+        let file = tokio::fs::OpenOptions::new().create(true).append(true).open(&self.path).await;
+        let result = match file {
+            Ok(mut file) => file.write_all(line.as_bytes()).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            tracing::warn!("Failed to write audit log {}: {}", self.path.display(), e);
+        }
+    }
+}
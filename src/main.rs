@@ -1,29 +1,48 @@
-//! Nushell MCP Server - Simplified Architecture
-//! Three tools: NuExec, NuRead, NuKill
-//! Principle: Consolidation - fewer, more general-purpose tools
+//! Nushell MCP Server
+//! Tools, by purpose:
+//! - Commands: nu.exec, nu.script
+//! - Background jobs and processes: nu.output, nu.wait, nu.kill, nu.killall, nu.signal, nu.detach, nu.ps, nu.stats
+//! - Session state: nu.cd, nu.pushd, nu.popd, nu.env, nu.env_snapshot, nu.env_restore
+//! - Files: nu.read, nu.write, nu.which, nu.grep, nu.find
+//! - Editing: nu.apply, nu.apply_confirm, nu.restore
+//! - Web: nu.search, nu.http, nu.fetch
 
 use rmcp::{
     handler::server::router::tool::ToolRouter,
+    handler::server::tool::ToolCallContext,
     handler::server::wrapper::Parameters,
-    model::{ServerCapabilities, ServerInfo, CallToolResult, Content},
-    tool, tool_handler, tool_router,
+    model::{
+        AnnotateAble, CallToolRequestParam, CallToolResult, Content, ListResourcesResult, ListToolsResult, Meta, PaginatedRequestParam,
+        ProgressNotificationParam, ProgressToken, RawResource, ReadResourceRequestParam,
+        ReadResourceResult, ResourceContents, ServerCapabilities, ServerInfo,
+    },
+    service::RequestContext,
+    tool, tool_router,
     transport::stdio,
-    ErrorData as McpError, ServiceExt,
+    ErrorData as McpError, Peer, RoleServer, ServiceExt,
 };
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use serde_json::json;
 use std::collections::HashMap;
-use tracing::{error, info};
+use std::time::Duration;
+use tracing::{error, info, warn};
 
+mod audit;
 mod exec;
 mod state;
 
-use exec::{NuApplyArgs, NuExecArgs, NuExecutor, NuFetchArgs, NuKillArgs, NuOutputArgs, NuSearchArgs};
-use state::AppState;
+use exec::{ApplyOptions, NuApplyArgs, NuApplyConfirmArgs, NuCdArgs, NuPopdArgs, NuEnvArgs, NuEnvRestoreArgs, NuExecArgs, NuExecutor, NuFetchArgs, NuFindArgs, NuGrepArgs, NuHttpArgs, NuDetachArgs, NuKillAllArgs, NuKillArgs, NuOutputArgs, NuPsArgs, NuReadArgs, NuRestoreArgs, NuScriptArgs, NuSearchArgs, NuSignalArgs, NuStatsArgs, NuWaitArgs, NuWhichArgs, NuWriteArgs, NuTable, SearchResultItem};
+use audit::{AuditEntry, AuditLog};
+use state::{format_bytes, AppState, DEFAULT_SESSION};
 
 #[derive(Clone)]
 pub struct NuServer {
     tool_router: ToolRouter<Self>,
     state: AppState,
     executor: NuExecutor,
+    /// Tool call trail (NU_AUDIT_LOG)
+    audit: Option<AuditLog>,
 }
 
 impl Default for NuServer {
@@ -36,11 +55,30 @@ impl Default for NuServer {
 impl NuServer {
     pub fn new() -> Self {
         let nu_path = std::env::var("NU_PATH").unwrap_or_else(|_| "nu".to_string());
+        let state = AppState::new();
 
+        // Reap finished jobs so their buffers don't accumulate forever
+        let ttl = Duration::from_secs(
+            std::env::var("NU_PROC_TTL_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(600),
+        );
+        let reaper_state = state.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval((ttl / 2).clamp(Duration::from_secs(1), Duration::from_secs(60)));
+            loop {
+                interval.tick().await;
+                let reaped = reaper_state.reap_finished(ttl).await;
+                if !reaped.is_empty() {
+                    info!("Reaped {} finished job(s): {}", reaped.len(), reaped.join(", "));
+                }
+            }
+        });
+
+        let executor = NuExecutor::new(nu_path, String::new());
         Self {
             tool_router: Self::tool_router(),
-            state: AppState::new(),
-            executor: NuExecutor::new(nu_path, String::new()),
+            state,
+            audit: AuditLog::from_env(executor.redact_patterns.clone()),
+            executor,
         }
     }
 
@@ -51,12 +89,27 @@ impl NuServer {
     /// Args:
     ///   command: Nushell pipeline to execute
     ///   background: If true, runs in background and returns job ID
-    ///   cwd: Working directory (optional)
+    ///   cwd: Working directory for this call only; the session CWD is unchanged (optional)
     ///   env: Environment variables (optional)
     ///   timeout: Timeout in seconds (optional, default 60)
+    ///   fail_on_nonzero: Return a tool error when the exit code is non-zero (optional)
+    ///   expect_success: Return an MCP error when the exit code is non-zero (optional)
+    ///   redact_patterns: Extra regexes to mask in output (optional, common tokens always masked)
+    ///   run_as: {uid, gid} to drop privileges to (optional, Unix only, see NU_RUN_AS_ALLOW)
+    ///   max_memory_mb / max_cpu_secs / max_open_files: rlimits for the child (optional, Unix only)
+    ///   compact: Return `[exit=0 45ms] <output>` on a single line (optional)
+    ///   output_format: "text" (default), "table" for structured {columns, rows}, or "yaml"/"toml" (optional)
+    ///   retries / retry_on_exit: Re-run on transient failures (optional, blocking only)
+    ///   output_to_file: Write the full untruncated, redacted output to this file (optional, blocking only; refused when read-only)
+    ///   log_file: Append every redacted output line to this file as JSON lines (optional, background only; refused when read-only)
+    ///   stdin: Data piped to the command's stdin, closed after writing (optional)
+    ///   as_json: Append `| to json -r` and return the parsed value as structured content (optional)
+    ///   keep_ansi: Keep ANSI color codes, which are stripped by default (optional)
+    ///   session: Session key with its own working directory (optional, default "default")
+    ///   scratch / keep_scratch: Run in a fresh temp directory, removed afterwards unless kept (optional)
     ///
     /// Returns:
-    ///   blocking: {exit_code, output, took_ms, success}
+    ///   blocking: {exit_code, output, took_ms, success, error_source, cwd}
     ///   background: {id, status, message}
     ///
     /// Examples:
@@ -104,133 +157,1120 @@ CONDITIONALS:
 - if/else: `if $age > 18 { "adult" } else { "minor" }`
 - match: `match $val { 1 => "one", 2 => "two", _ => "other" }`
 
-LOOPS:
-- For: `for x in 1..5 { print $x }`
-- While: `mut i = 0; while $i < 5 { print $i; $i += 1 }`
-- Each: `[1 2 3] | each { |x| $x * 2 }`
+LOOPS:
+- For: `for x in 1..5 { print $x }`
+- While: `mut i = 0; while $i < 5 { print $i; $i += 1 }`
+- Each: `[1 2 3] | each { |x| $x * 2 }`
+
+EXTERNAL COMMANDS:
+- Prefix with `^`: `^git status`, `^cargo build`
+- Capture output: `let out = (^git status | complete)
+- Feed input: set stdin="..." → read it with `$in` or an external like `^wc -l`
+
+AVOID BASHISMS - use Nushell native:
+- Instead of `cat`: use `open`
+- Instead of `grep`: use `where` with string operations
+- Instead of `sed/awk`: use `str replace`, `update`, `select`
+- Instead of `find`: use `ls` with filters
+- Instead of `&&`: use `;` for chaining
+- Instead of `|`: use `|` (same, but data is structured)
+- Instead of `$VAR`: use `$env.VAR` or `$var`
+- Instead of `$(cmd)`: use `(cmd)` or `^cmd`
+- Instead of `sort | uniq`: use `lines | uniq` for text, or just `uniq` for lists
+- If the server sets NU_REWRITE_BASHISMS=1, `&&`, `$(cmd)` and `cat file` are translated for you and a `[rewrite]` line says so
+
+OUTPUT FORMATTING:
+- To see stdout: pipe to `print` → `ls | print`
+- To get JSON: pipe to `to json` → `ls | to json | print`
+- To CSV: pipe to `to csv` → `ls | to csv`
+- Truncate large output: `ls | take 50 | to json`
+- Structured table: set output_format="table" → `ls | select name size` returns {columns, rows}
+- YAML/TOML result: set output_format="yaml" or "toml"; JSON output (`... | to json`) is converted too
+- JSON value: set as_json=true → `ls | select name size` returns {exit_code, took_ms, cwd, value}; falls back to text with a warning if the output is not JSON
+- ANSI color codes are stripped from output; set keep_ansi=true if you need them
+
+SESSIONS:
+- `cd` persists between calls, except in calls that pass `cwd` (a one-off override). Pass session="<name>" to give each concurrent agent its own working directory; calls without it share the "default" session
+
+SCRATCH DIRECTORY:
+- Set scratch=true to run in a fresh temp directory (e.g. untrusted generated code); the session CWD is unchanged
+- Blocking runs remove it afterwards unless keep_scratch=true; background runs always keep it. The path is reported as `Scratch:`
+
+LONG-RUNNING JOBS:
+- Set log_file="build.jsonl" with background=true to persist every output line as JSON lines {t_ms, stream, line}; the in-memory buffer stays capped, nu.output reports the log path and size
+
+WARNING:
+- Avoid searching in 'target/', '.git/', '.cache/', 'node_modules/', '.venv/' (timeouts/encoding errors); use nu.grep for text search and nu.find for file discovery
+- Use `| take N BEFORE | to json` for large results
+- If command times out, search in specific subdirectory instead
+- Quote file paths with spaces: `"my path/file.txt""#
+    )]
+    pub async fn nu_exec(
+        &self,
+        args: Parameters<NuExecArgs>,
+        meta: Meta,
+        peer: Peer<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        let args = &args.0;
+        // Session environment first, per-call variables override it
+        let mut env = self.state.get_env().await;
+        env.extend(args.env.as_ref().unwrap_or(&HashMap::new()).clone());
+
+        if args.log_file.is_some() && !args.background {
+            return Err(McpError::invalid_params("log_file requires background=true (use output_to_file for blocking commands)", None));
+        }
+
+        if args.scratch.unwrap_or(false) && args.cwd.is_some() {
+            return Err(McpError::invalid_params("scratch and cwd cannot be combined", None));
+        }
+        let session = args.session.as_deref().unwrap_or(DEFAULT_SESSION);
+        let session_cwd = self.state.get_cwd(session).await;
+        let requested_cwd = match &args.cwd {
+            Some(cwd) => Some(
+                self.executor
+                    .resolve_dir(&session_cwd, cwd)
+                    .await
+                    .and_then(|dir| self.executor.check_root(&self.state, &dir).map(|_| dir))
+                    .map_err(|e| McpError::invalid_params(format!("Invalid cwd: {e}"), None))?,
+            ),
+            None => None,
+        };
+        let mut spawn_options = self.executor
+            .resolve_spawn_options(args)
+            .map_err(|e| McpError::invalid_params(e.to_string(), None))?;
+
+        // Table mode serializes the pipeline result so it can be parsed back into rows
+        let (table_mode, doc_format) = match args.output_format.as_deref() {
+            None | Some("text") => (false, None),
+            Some("table") => (true, None),
+            Some(format @ ("yaml" | "toml")) => (false, Some(format)),
+            Some(other) => {
+                return Err(McpError::invalid_params(format!("Unknown output_format '{}': expected text, table, yaml or toml", other), None));
+            }
+        };
+        let as_json = args.as_json.unwrap_or(false);
+        if as_json && (args.background || table_mode || doc_format.is_some()) {
+            return Err(McpError::invalid_params("as_json cannot be combined with background or output_format", None));
+        }
+        let command = if table_mode && !args.background {
+            format!("({}) | to json --raw", args.command.trim().trim_end_matches(';'))
+        } else if as_json && !ends_with_to_json(&args.command) {
+            format!("{} | to json -r", args.command.trim().trim_end_matches(';'))
+        } else {
+            args.command.clone()
+        };
+
+        let redactions = self.executor
+            .resolve_redactions(args.redact_patterns.as_deref())
+            .map_err(|e| McpError::invalid_params(e.to_string(), None))?;
+
+        // Scratch mode runs in a fresh temp dir and leaves the session CWD untouched; it is created
+        // only after every argument check so a rejected call leaves nothing behind
+        let scratch_dir = if args.scratch.unwrap_or(false) {
+            let dir = std::env::temp_dir()
+                .join("nu-mcp")
+                .join(format!("scratch_{}", nanoid::nanoid!(8)));
+            tokio::fs::create_dir_all(&dir)
+                .await
+                .map_err(|e| McpError::internal_error(format!("Failed to create scratch dir {}: {e}", dir.display()), None))?;
+            Some(dir)
+        } else {
+            None
+        };
+        // A scratch dir or explicit cwd applies to this call only; the shared session CWD is never touched
+        spawn_options.cwd_override = scratch_dir
+            .as_ref()
+            .map(|dir| dir.to_string_lossy().to_string())
+            .or(requested_cwd);
+
+        let result = if args.background {
+            let bg_result = match self.executor.exec_background(&self.state, &command, &env, &redactions, &spawn_options).await {
+                Ok(bg_result) => bg_result,
+                Err(e) => {
+                    if let Some(ref dir) = scratch_dir {
+                        let _ = tokio::fs::remove_dir_all(dir).await;
+                    }
+                    return Err(McpError::invalid_request(format!("exec_background failed: {e}"), None));
+                }
+            };
+
+            // The job may still be using the scratch dir, so it is never removed here
+            let scratch_line = match scratch_dir {
+                Some(ref dir) => format!("\nScratch: {}", dir.display()),
+                None => String::new(),
+            };
+            let log_line = match args.log_file {
+                Some(ref file) => format!("\nLog file: {}", file),
+                None => String::new(),
+            };
+            format!("Background process started.\nID: {}\nStatus: {}\n{}{}{}", bg_result.id, bg_result.status, bg_result.message, scratch_line, log_line)
+        } else {
+            let timeout = self.executor.resolve_timeout(args.timeout);
+            // Clients that sent a progress token get periodic elapsed-time/line-count updates
+            let progress_task = meta
+                .get_progress_token()
+                .map(|token| spawn_progress_reporter(peer, token, spawn_options.lines_seen.clone()));
+
+            // Re-run on transient failures with a short backoff between attempts
+            let max_attempts = args.retries.unwrap_or(0) as u32 + 1;
+            let mut attempts = 1;
+            let exec_result = loop {
+                let exec_result = match self.executor
+                    .exec_blocking(&self.state, &command, &env, timeout, &redactions, &spawn_options)
+                    .await
+                {
+                    Ok(exec_result) => exec_result,
+                    Err(e) => break Err(McpError::invalid_request(format!("exec_blocking failed: {e}"), None)),
+                };
+
+                if attempts < max_attempts && NuExecutor::should_retry(exec_result.exit_code, args.retry_on_exit.as_deref()) {
+                    let backoff = NuExecutor::retry_backoff(attempts);
+                    info!("Attempt {} exited with {}, retrying in {:?}", attempts, exec_result.exit_code, backoff);
+                    tokio::time::sleep(backoff).await;
+                    attempts += 1;
+                    continue;
+                }
+                break Ok(exec_result);
+            };
+            if let Some(task) = progress_task {
+                task.abort();
+            }
+
+            // Drop the scratch dir unless asked to keep it
+            let scratch_note = match scratch_dir {
+                Some(ref dir) => {
+                    if args.keep_scratch.unwrap_or(false) {
+                        Some(format!("{} (kept)", dir.display()))
+                    } else {
+                        if let Err(e) = tokio::fs::remove_dir_all(dir).await {
+                            warn!("Failed to remove scratch dir {}: {}", dir.display(), e);
+                        }
+                        Some(format!("{} (removed)", dir.display()))
+                    }
+                }
+                None => None,
+            };
+            let exec_result = exec_result?;
+
+            let rendered = doc_format.and_then(|format| match exec_result.render(format) {
+                Ok(text) => Some(text),
+                Err(e) => {
+                    warn!("{} output unavailable, returning text: {}", format, e);
+                    None
+                }
+            });
+
+            let text = if let Some(rendered) = rendered {
+                rendered
+            } else if args.compact.unwrap_or(false) {
+                // Terse single-line summary; full output only when it is short
+                let output = exec_result.output.trim();
+                let body = if output.len() <= 200 {
+                    output.to_string()
+                } else {
+                    let first_line = output.lines().next().unwrap_or("");
+                    format!("{} (+{} more lines)", first_line, output.lines().count().saturating_sub(1))
+                };
+                let scratch = scratch_note.as_ref().map(|note| format!(" [scratch: {}]", note)).unwrap_or_default();
+                format!("[exit={} {}ms]{} {}", exec_result.exit_code, exec_result.took_ms, scratch, body)
+            } else {
+                let error_line = match exec_result.error_source.as_deref() {
+                    Some(source) if source != "none" => format!("Error source: {}\n", source),
+                    _ => String::new(),
+                };
+                let attempts_line = if max_attempts > 1 {
+                    format!("Attempts: {}\n", attempts)
+                } else {
+                    String::new()
+                };
+                let file_line = match exec_result.output_file {
+                    Some(ref path) => format!("Full output: {}\n", path),
+                    None => String::new(),
+                };
+                let scratch_line = match scratch_note {
+                    Some(ref note) => format!("Scratch: {}\n", note),
+                    None => String::new(),
+                };
+                format!("Exit code: {}\nTime: {}ms\nCWD: {}\n{}{}{}{}\n{}",
+                    exec_result.exit_code,
+                    exec_result.took_ms,
+                    exec_result.cwd,
+                    attempts_line,
+                    error_line,
+                    file_line,
+                    scratch_line,
+                    exec_result.output
+                )
+            };
+
+            // Surface failed commands through tool-error semantics when requested
+            if args.fail_on_nonzero.unwrap_or(false) && exec_result.exit_code != 0 {
+                return Ok(CallToolResult::error(vec![Content::text(text)]));
+            }
+
+            // Or escalate to a protocol error so the client's error path triggers
+            if args.expect_success.unwrap_or(false) && exec_result.exit_code != 0 {
+                return Err(McpError::internal_error(
+                    format!("command failed with exit code {}\n{}", exec_result.exit_code, text),
+                    Some(json!({
+                        "exit_code": exec_result.exit_code,
+                        "took_ms": exec_result.took_ms,
+                        "error_source": exec_result.error_source,
+                    })),
+                ));
+            }
+
+            if table_mode && exec_result.exit_code == 0 {
+                match NuTable::from_json(&exec_result.stdout) {
+                    Ok(table) => {
+                        return Ok(CallToolResult::structured(json!({
+                            "exit_code": exec_result.exit_code,
+                            "took_ms": exec_result.took_ms,
+                            "cwd": exec_result.cwd,
+                            "columns": table.columns,
+                            "rows": table.rows,
+                        })));
+                    }
+                    Err(e) => warn!("Table output unavailable, returning text: {}", e),
+                }
+            }
+
+            if as_json && exec_result.exit_code == 0 {
+                match serde_json::from_str::<serde_json::Value>(exec_result.stdout.trim()) {
+                    Ok(value) => {
+                        return Ok(CallToolResult::structured(json!({
+                            "exit_code": exec_result.exit_code,
+                            "took_ms": exec_result.took_ms,
+                            "cwd": exec_result.cwd,
+                            "value": value,
+                        })));
+                    }
+                    Err(e) => {
+                        warn!("as_json output is not JSON, returning text: {}", e);
+                        let reason = if exec_result.stdout.trim().is_empty() { "produced no output" } else { "was not valid JSON" };
+                        return Ok(CallToolResult::success(vec![Content::text(format!(
+                            "{}\nWarning: as_json requested but the pipeline {}; returned as text",
+                            text, reason
+                        ))]));
+                    }
+                }
+            }
+
+            text
+        };
+
+        Ok(CallToolResult::success(vec![Content::text(result)]))
+    }
+
+    /// NuScript - Run a multi-line Nushell script
+    ///
+    /// Use this instead of nu.exec when the code spans many lines or nests quotes.
+    ///
+    /// Args:
+    ///   script: Inline script source (or script_path)
+    ///   script_path: Existing .nu file to run (or script)
+    ///   args: Arguments for the script's `def main` (optional)
+    ///   env: Extra environment variables (optional)
+    ///   timeout: Timeout in seconds (optional, default 60)
+    ///   session: Session whose CWD the script starts in (optional)
+    ///
+    /// Returns:
+    ///   {exit_code, output, took_ms, success}
+    #[tool(
+        name = "nu.script",
+        description = r#"Run a multi-line Nushell script as `nu <file>` instead of `nu -c`, so nested quotes need no escaping and errors point at real line numbers.
+
+ARGS:
+- script: Inline script source; written to a temp .nu file that is removed afterwards
+- script_path: Existing .nu file to run instead (relative to the session CWD)
+- args: Arguments passed to the script's `def main` (optional)
+- env / timeout / session: Same as nu.exec
+
+NOTES:
+- Pass exactly one of script or script_path
+- The script starts in the session CWD; a `cd` inside it does not persist to later calls
+- Nothing is appended to the script: use `print` for output you want to see
+- NU_READONLY and NU_DENY_COMMANDS/NU_ALLOW_COMMANDS apply to the script source; NU_REWRITE_RULES do not"#
+    )]
+    pub async fn nu_script(&self, args: Parameters<NuScriptArgs>) -> Result<CallToolResult, McpError> {
+        let args = &args.0;
+        let mut env = self.state.get_env().await;
+        env.extend(args.env.clone().unwrap_or_default());
+
+        let result = self.executor
+            .exec_script(&self.state, args, &env)
+            .await
+            .map_err(|e| McpError::invalid_request(format!("exec_script failed: {e}"), None))?;
+
+        let error_line = match result.error_source.as_deref() {
+            Some(source) if source != "none" => format!("Error source: {}\n", source),
+            _ => String::new(),
+        };
+        let text = format!("Exit code: {}\nTime: {}ms\n{}\n{}", result.exit_code, result.took_ms, error_line, result.output);
+        Ok(CallToolResult::success(vec![Content::text(text)]))
+    }
+
+    /// NuOutput - Read output from background process
+    ///
+    /// Use this to get output from processes started with background=true.
+    ///
+    /// Args:
+    ///   id: Job ID from NuExec
+    ///   offset: Offset from a previous call (optional)
+    ///   wait_ms: Long-poll up to this long for output past offset (optional)
+    ///   since_seq: Return sequenced chunks after this number (optional, 0 to start)
+    ///   split_streams: Show stdout and stderr as separate sections (optional)
+    ///   since_line / max_lines: Return only lines after this index, with a next_line cursor (optional)
+    ///   filter / invert: Keep only lines matching (or not matching) a regex (optional)
+    ///
+    /// Returns:
+    ///   {id, status, output, stdout, stderr, exit_code?, took_secs?, offset}
+    #[tool(
+        name = "nu.output",
+        description = r#"Retrieves output from a running or completed background process started via `nu.exec`.
+
+Returns current buffer snapshot immediately. Output includes stdout with stderr appended (marked with [stderr] if present).
+
+LONG-POLL: pass the returned `Offset` back as `offset` together with `wait_ms` to wait (up to 60s) until new output arrives or the job finishes, instead of polling in a tight loop.
+
+CHUNK MODE: pass `since_seq` (0 to start) to receive output as `[seq stream] line` chunks with increasing sequence numbers. Pass the returned `Next seq` back to continue; a `[seq final]` chunk marks the end of the stream, and a `Gap:` line reports evicted chunks.
+
+LINE MODE: pass `since_line` (0 to start) and optionally `max_lines` to read only new lines of the combined output. Pass the returned `Next line` back as `since_line` to follow the log incrementally. Line numbers refer to the buffered output, so they stop advancing once the buffer limit is reached.
+
+SPLIT STREAMS: pass `split_streams: true` to get separate `Stdout:` and `Stderr:` sections, e.g. to filter compiler warnings independently of build output.
+
+FILTER: pass `filter` (a regex) to return only matching lines, e.g. `filter: "(?i)error|panic"` to tail just the errors of a noisy dev server; add `invert: true` to drop matching lines instead. Works with line mode and split streams, not with chunk mode."#
+    )]
+    pub async fn nu_output(&self, args: Parameters<NuOutputArgs>) -> Result<CallToolResult, McpError> {
+        let args = &args.0;
+
+        if args.filter.is_some() && args.since_seq.is_some() {
+            return Err(McpError::invalid_params("filter cannot be combined with since_seq", None));
+        }
+
+        let result = self.executor
+            .read_output(
+                &self.state,
+                &args.id,
+                args.offset,
+                args.wait_ms,
+                args.filter.as_deref(),
+                args.invert.unwrap_or(false),
+            )
+            .await
+            .map_err(|e| McpError::invalid_request(format!("read_output failed: {e}"), None))?;
+
+        // Chunk mode replaces the flat buffer with sequenced pieces
+        let body = match args.since_seq {
+            Some(since_seq) => {
+                let chunks = self.executor
+                    .read_chunks(&self.state, &args.id, since_seq)
+                    .await
+                    .map_err(|e| McpError::invalid_request(format!("read_chunks failed: {e}"), None))?;
+                let next_seq = chunks.last().map(|c| c.seq).unwrap_or(since_seq);
+                let mut body = format!("Next seq: {}\n", next_seq);
+                if chunks.first().is_some_and(|c| c.seq > since_seq + 1) {
+                    body.push_str(&format!("Gap: chunks {}..{} were evicted\n", since_seq + 1, chunks[0].seq - 1));
+                }
+                for chunk in &chunks {
+                    if chunk.is_final {
+                        body.push_str(&format!("[{} final]\n", chunk.seq));
+                    } else {
+                        body.push_str(&format!("[{} {}] {}", chunk.seq, chunk.stream, chunk.data));
+                    }
+                }
+                body
+            }
+            None if args.since_line.is_some() => {
+                let (lines, next_line) = result.line_window(args.since_line.unwrap_or(0), args.max_lines);
+                format!("Next line: {}\n{}", next_line, lines.join("\n"))
+            }
+            None if args.split_streams.unwrap_or(false) => {
+                format!("Stdout:\n{}\n\nStderr:\n{}", result.stdout, result.stderr)
+            }
+            None => result.output,
+        };
+
+        let log_line = match (&result.log_file, result.log_file_bytes) {
+            (Some(path), Some(bytes)) => format!("Log file: {} ({})\n", path, format_bytes(bytes as usize)),
+            (Some(path), None) => format!("Log file: {} (missing)\n", path),
+            _ => String::new(),
+        };
+        let text = format!("ID: {}\nStatus: {}\nRunning for: {}s\nExit code: {}\nOffset: {}\n{}\n{}",
+            result.id,
+            result.status,
+            result.took_secs,
+            result.exit_code.map(|c| c.to_string()).unwrap_or_else(|| "running".to_string()),
+            result.offset,
+            log_line,
+            body
+        );
+
+        Ok(CallToolResult::success(vec![Content::text(text)]))
+    }
+
+    /// NuWait - Block until a background process finishes
+    ///
+    /// Use this instead of polling nu.output in a loop.
+    ///
+    /// Args:
+    ///   id: Job ID from NuExec
+    ///   timeout: Seconds to wait (optional, default 60)
+    ///
+    /// Returns:
+    ///   {id, status, output, exit_code?, took_secs, timed_out}
+    #[tool(
+        name = "nu.wait",
+        description = r#"Wait for a background process started via `nu.exec` to finish and return its exit code and full buffered output.
+
+ARGS:
+- id: Job ID returned by nu.exec with background=true
+- timeout: Seconds to wait (default: 60)
+
+NOTES:
+- If the job is still running when the timeout elapses, the current output is returned with `Timed out: true` instead of an error; call again to keep waiting
+- Returns immediately for jobs that already finished or were detached"#
+    )]
+    pub async fn nu_wait(&self, args: Parameters<NuWaitArgs>) -> Result<CallToolResult, McpError> {
+        let args = &args.0;
+
+        let result = self.executor
+            .wait_process(&self.state, &args.id, args.timeout)
+            .await
+            .map_err(|e| McpError::invalid_request(format!("wait_process failed: {e}"), None))?;
+
+        let text = format!("ID: {}\nStatus: {}\nRunning for: {}s\nExit code: {}\nTimed out: {}\n\n{}",
+            result.id,
+            result.status,
+            result.took_secs,
+            result.exit_code.map(|c| c.to_string()).unwrap_or_else(|| "running".to_string()),
+            result.timed_out,
+            result.output
+        );
+
+        Ok(CallToolResult::success(vec![Content::text(text)]))
+    }
+
+    /// NuKill - Kill a background process
+    ///
+    /// Use this to terminate a running background process.
+    ///
+    /// Args:
+    ///   id: Job ID to kill
+    ///   grace_secs: Seconds between SIGTERM and SIGKILL (optional, default 3)
+    ///
+    /// Returns:
+    ///   {id, status, command, ran_for_secs, had_output}
+    #[tool(
+        name = "nu.kill",
+        description = r#"Terminate a running background process by its job ID to release system resources.
+
+On Unix the process first receives SIGTERM so it can clean up, then SIGKILL if it is still running after `grace_secs` (default: 3). Signals go to the job's whole process group, so subprocesses it spawned are stopped too. Status is "terminated" for a graceful exit and "killed" when SIGKILL was needed.
+
+With NU_STATE_FILE set, jobs started before a server restart can still be killed by their old job ID."#
+    )]
+    pub async fn nu_kill(&self, args: Parameters<NuKillArgs>) -> Result<CallToolResult, McpError> {
+        let args = &args.0;
+
+        let result = self.executor
+            .kill_process(&self.state, &args.id, Duration::from_secs(args.grace_secs.unwrap_or(3)))
+            .await
+            .map_err(|e| McpError::invalid_request(format!("kill_process failed: {e}"), None))?;
+
+        let text = format!("ID: {}\nStatus: {}\nCommand: {}\nRan for: {}s\nHad output: {}",
+            result.id,
+            result.status,
+            result.command,
+            result.ran_for_secs,
+            result.had_output
+        );
+        Ok(CallToolResult::success(vec![Content::text(text)]))
+    }
+
+    /// NuKillAll - Kill every running background process
+    ///
+    /// Use this on session teardown so background servers and watchers are not leaked.
+    ///
+    /// Args:
+    ///   grace_secs: Seconds between SIGTERM and SIGKILL (optional, default 3)
+    ///
+    /// Returns:
+    ///   {jobs: [{id, status, command, ran_for_secs, had_output}], errors}
+    #[tool(
+        name = "nu.killall",
+        description = r#"Terminate every running background job started via `nu.exec`, e.g. when a session ends. Returns how many were stopped and the status of each.
+
+ARGS:
+- grace_secs: Seconds between SIGTERM and SIGKILL (default: 3)
+
+NOTES:
+- Jobs are stopped in parallel, with the same SIGTERM-then-SIGKILL sequence as nu.kill, so the call takes about one grace period
+- Jobs released with nu.detach and jobs that already finished are left alone
+- Nothing running is not an error: the result just reports 0 jobs"#
+    )]
+    pub async fn nu_killall(&self, args: Parameters<NuKillAllArgs>) -> Result<CallToolResult, McpError> {
+        let args = &args.0;
+
+        let result = self.executor
+            .kill_all(&self.state, Duration::from_secs(args.grace_secs.unwrap_or(3)))
+            .await;
+
+        let mut text = format!("Stopped: {} jobs", result.jobs.len());
+        for job in &result.jobs {
+            text.push_str(&format!("\n{}  {}  {}s  {}", job.id, job.status, job.ran_for_secs, job.command));
+        }
+        if !result.errors.is_empty() {
+            text.push_str(&format!("\n\nErrors:\n{}", result.errors.join("\n")));
+        }
+        Ok(CallToolResult::success(vec![Content::text(text)]))
+    }
+
+    /// NuSignal - Send a signal to a background process
+    ///
+    /// Use this to interrupt or reload a job without killing it (Unix only).
+    ///
+    /// Args:
+    ///   id: Job ID
+    ///   signal: Signal name ("INT", "HUP", "USR1") or number
+    ///
+    /// Returns:
+    ///   {id, signal, status, exit_code?}
+    #[tool(
+        name = "nu.signal",
+        description = r#"Send a Unix signal to a background process, e.g. to interrupt a REPL or make a server reload.
+
+ARGS:
+- id: Job ID returned by nu.exec with background=true
+- signal: Name like "INT", "HUP", "USR1" (the "SIG" prefix is optional) or a number
+
+NOTES:
+- Returns the job status shortly after delivery
+- Not supported on Windows; use nu.kill there"#
+    )]
+    pub async fn nu_signal(&self, args: Parameters<NuSignalArgs>) -> Result<CallToolResult, McpError> {
+        let args = &args.0;
+
+        let result = self.executor
+            .signal_process(&self.state, &args.id, &args.signal)
+            .await
+            .map_err(|e| McpError::invalid_request(format!("signal_process failed: {e}"), None))?;
+
+        let text = format!("ID: {}\nSignal: {}\nStatus: {}\nExit code: {}",
+            result.id,
+            result.signal,
+            result.status,
+            result.exit_code.map(|c| c.to_string()).unwrap_or_else(|| "running".to_string()),
+        );
+        Ok(CallToolResult::success(vec![Content::text(text)]))
+    }
+
+    /// NuDetach - Stop monitoring a background process without killing it
+    ///
+    /// Use this for long-running servers whose output is no longer needed.
+    ///
+    /// Args:
+    ///   id: Job ID to detach
+    ///
+    /// Returns:
+    ///   {id, status, message}
+    #[tool(
+        name = "nu.detach",
+        description = r#"Stop buffering output from a background process while leaving it running.
+
+ARGS:
+- id: Job ID returned by nu.exec with background=true
+
+NOTES:
+- The job's status becomes "detached"; output already buffered stays readable via nu.output
+- New output is read and discarded so the process never blocks on a full pipe
+- nu.kill still terminates a detached job"#
+    )]
+    pub async fn nu_detach(&self, args: Parameters<NuDetachArgs>) -> Result<CallToolResult, McpError> {
+        let args = &args.0;
+
+        let result = self.executor
+            .detach_process(&self.state, &args.id)
+            .await
+            .map_err(|e| McpError::invalid_request(format!("detach_process failed: {e}"), None))?;
+
+        let text = format!("ID: {}\nStatus: {}\n{}", result.id, result.status, result.message);
+        Ok(CallToolResult::success(vec![Content::text(text)]))
+    }
+
+    /// NuPs - List system processes
+    ///
+    /// Use this to diagnose resource usage across the whole system, not only jobs started here.
+    ///
+    /// Args:
+    ///   filter: Case-insensitive name substring (optional)
+    ///   limit: Max processes to return (optional, default 50, max 500)
+    ///
+    /// Returns:
+    ///   {total, matched, processes: [{pid, name, cpu_percent, memory_bytes}]}
+    #[tool(
+        name = "nu.ps",
+        description = r#"List system processes with PID, name, CPU% and memory as structured data, sorted by CPU usage.
+
+ARGS:
+- filter: Only processes whose name contains this text (case-insensitive)
+- limit: Max processes to return (default: 50, max: 500)
+
+NOTES:
+- Covers every process on the system, not only background jobs from nu.exec (use nu.output for those)
+- Takes ~200ms because CPU usage is sampled over an interval"#
+    )]
+    pub async fn nu_ps(&self, args: Parameters<NuPsArgs>) -> Result<CallToolResult, McpError> {
+        let args = &args.0;
+
+        let result = self.executor
+            .list_processes(args)
+            .await
+            .map_err(|e| McpError::internal_error(format!("list_processes failed: {e}"), None))?;
+
+        let value = serde_json::to_value(&result)
+            .map_err(|e| McpError::internal_error(format!("Failed to serialize processes: {e}"), None))?;
+        Ok(CallToolResult::structured(value))
+    }
+
+    /// NuStats - Resource usage of background jobs
+    ///
+    /// Use this to find the runaway background job before reaching for nu.kill.
+    ///
+    /// Args:
+    ///   id: Job ID (optional, default all tracked jobs)
+    ///
+    /// Returns:
+    ///   {jobs: [{id, command, status, pid, uptime_secs, exit_code?, process_count?, cpu_percent?, memory_bytes?}]}
+    #[tool(
+        name = "nu.stats",
+        description = r#"Report CPU%, memory (RSS) and uptime of background jobs started via `nu.exec`, as structured data.
+
+ARGS:
+- id: Job ID (omit to summarize every tracked job)
+
+NOTES:
+- CPU and memory are summed over the job's process and everything it spawned; `process_count` says how many processes that is
+- Jobs that already exited report their status and exit code instead of usage
+- Takes ~200ms because CPU usage is sampled over an interval
+- For processes not started here, use nu.ps"#
+    )]
+    pub async fn nu_stats(&self, args: Parameters<NuStatsArgs>) -> Result<CallToolResult, McpError> {
+        let args = &args.0;
+
+        let result = self.executor
+            .job_stats(&self.state, args.id.as_deref())
+            .await
+            .map_err(|e| McpError::invalid_request(format!("job_stats failed: {e}"), None))?;
+
+        let value = serde_json::to_value(&result)
+            .map_err(|e| McpError::internal_error(format!("Failed to serialize job stats: {e}"), None))?;
+        Ok(CallToolResult::structured(value))
+    }
+
+    /// NuCd - Change the working directory
+    ///
+    /// Args:
+    ///   path: Directory to change to (absolute or relative)
+    ///   session: Session key (optional, default "default")
+    ///
+    /// Returns:
+    ///   {cwd}
+    #[tool(
+        name = "nu.cd",
+        description = r#"Change the working directory used by later nu.exec calls. The path must be an existing directory; relative paths resolve against the current directory. Returns the new CWD."#
+    )]
+    pub async fn nu_cd(&self, args: Parameters<NuCdArgs>) -> Result<CallToolResult, McpError> {
+        let args = &args.0;
+        let session = args.session.as_deref().unwrap_or(DEFAULT_SESSION);
+
+        let cwd = self.executor
+            .change_dir(&self.state, session, &args.path)
+            .await
+            .map_err(|e| McpError::invalid_params(e.to_string(), None))?;
+
+        Ok(CallToolResult::success(vec![Content::text(format!("CWD: {}", cwd))]))
+    }
+
+    /// NuPushd - Change directory and remember the current one
+    ///
+    /// Args:
+    ///   path: Directory to change to (absolute or relative)
+    ///   session: Session key (optional, default "default")
+    ///
+    /// Returns:
+    ///   {cwd, stack_depth}
+    #[tool(
+        name = "nu.pushd",
+        description = r#"Change the working directory like nu.cd, saving the current one on a stack. Use nu.popd to return to it."#
+    )]
+    pub async fn nu_pushd(&self, args: Parameters<NuCdArgs>) -> Result<CallToolResult, McpError> {
+        let args = &args.0;
+        let session = args.session.as_deref().unwrap_or(DEFAULT_SESSION);
+
+        let result = self.executor
+            .push_dir(&self.state, session, &args.path)
+            .await
+            .map_err(|e| McpError::invalid_params(e.to_string(), None))?;
+
+        let text = format!("CWD: {}\nStack depth: {}", result.cwd, result.stack_depth);
+        Ok(CallToolResult::success(vec![Content::text(text)]))
+    }
+
+    /// NuPopd - Return to the directory saved by nu.pushd
+    ///
+    /// Args:
+    ///   session: Session key (optional, default "default")
+    ///
+    /// Returns:
+    ///   {cwd, stack_depth}
+    #[tool(
+        name = "nu.popd",
+        description = r#"Return to the working directory saved by the most recent nu.pushd. Fails if the stack is empty or the directory no longer exists."#
+    )]
+    pub async fn nu_popd(&self, args: Parameters<NuPopdArgs>) -> Result<CallToolResult, McpError> {
+        let args = &args.0;
+        let session = args.session.as_deref().unwrap_or(DEFAULT_SESSION);
+
+        let result = self.executor
+            .pop_dir(&self.state, session)
+            .await
+            .map_err(|e| McpError::invalid_request(e.to_string(), None))?;
+
+        let text = format!("CWD: {}\nStack depth: {}", result.cwd, result.stack_depth);
+        Ok(CallToolResult::success(vec![Content::text(text)]))
+    }
+
+    /// NuEnv - Inspect and change the session environment
+    ///
+    /// Args:
+    ///   op: get, set, unset or list (default: list)
+    ///   name: Variable name (get/set/unset)
+    ///   value: New value (set)
+    ///
+    /// Returns:
+    ///   The variable(s) affected
+    #[tool(
+        name = "nu.env",
+        description = r#"Manage environment variables that persist across nu.exec calls, like export in a shell session.
+
+OPERATIONS:
+- list: Show all session variables (default)
+- get: Show one variable (name)
+- set: Set a variable (name, value)
+- unset: Remove a variable (name)
+
+NOTES:
+- Session variables are merged into every nu.exec child environment; a per-call env with the same name wins
+- nu.env_snapshot / nu.env_restore checkpoint and roll back these variables together with the CWD"#
+    )]
+    pub async fn nu_env(&self, args: Parameters<NuEnvArgs>) -> Result<CallToolResult, McpError> {
+        let args = &args.0;
+        let require_name = || {
+            args.name
+                .as_deref()
+                .filter(|n| !n.is_empty())
+                .ok_or_else(|| McpError::invalid_params("name is required for get, set and unset", None))
+        };
+
+        let text = match args.op.as_deref().unwrap_or("list") {
+            "list" => {
+                let mut vars: Vec<_> = self.state.get_env().await.into_iter().collect();
+                vars.sort();
+                let mut text = format!("Session variables: {}\n", vars.len());
+                for (name, value) in vars {
+                    text.push_str(&format!("{}={}\n", name, value));
+                }
+                text
+            }
+            "get" => {
+                let name = require_name()?;
+                match self.state.get_env().await.get(name) {
+                    Some(value) => format!("{}={}", name, value),
+                    None => format!("{} is not set", name),
+                }
+            }
+            "set" => {
+                let name = require_name()?;
+                if name.contains('=') || name.contains('\0') {
+                    return Err(McpError::invalid_params(format!("Invalid variable name: {}", name), None));
+                }
+                let value = args.value.clone()
+                    .ok_or_else(|| McpError::invalid_params("value is required for set", None))?;
+                let previous = self.state.set_env_var(name.to_string(), value.clone()).await;
+                match previous {
+                    Some(previous) => format!("{}={} (was {})", name, value, previous),
+                    None => format!("{}={}", name, value),
+                }
+            }
+            "unset" => {
+                let name = require_name()?;
+                match self.state.unset_env_var(name).await {
+                    Some(previous) => format!("Unset {} (was {})", name, previous),
+                    None => format!("{} was not set", name),
+                }
+            }
+            other => {
+                return Err(McpError::invalid_params(format!("Unknown op '{}': expected get, set, unset or list", other), None));
+            }
+        };
+
+        Ok(CallToolResult::success(vec![Content::text(text)]))
+    }
+
+    /// NuEnvSnapshot - Checkpoint the session state
+    ///
+    /// Captures the current working directory and session environment.
+    ///
+    /// Returns:
+    ///   {token, cwd, env_vars}
+    #[tool(
+        name = "nu.env_snapshot",
+        description = r#"Capture the current session state (working directory of the "default" session and session environment variables) and return a token. Pass the token to `nu.env_restore` to roll back later. Up to 32 snapshots are kept; the oldest is evicted first."#
+    )]
+    pub async fn nu_env_snapshot(&self) -> Result<CallToolResult, McpError> {
+        let token = self.state.snapshot_env().await;
+        let cwd = self.state.get_cwd(DEFAULT_SESSION).await;
+        let env_count = self.state.get_env().await.len();
+
+        let text = format!("Token: {}\nCWD: {}\nEnv vars: {}", token, cwd, env_count);
+        Ok(CallToolResult::success(vec![Content::text(text)]))
+    }
+
+    /// NuEnvRestore - Roll back to a session checkpoint
+    ///
+    /// Args:
+    ///   token: Token from nu.env_snapshot
+    ///
+    /// Returns:
+    ///   {token, cwd, env_vars}
+    #[tool(
+        name = "nu.env_restore",
+        description = r#"Restore the working directory and session environment captured by `nu.env_snapshot`. The snapshot is kept, so it can be restored again."#
+    )]
+    pub async fn nu_env_restore(&self, args: Parameters<NuEnvRestoreArgs>) -> Result<CallToolResult, McpError> {
+        let args = &args.0;
+
+        let snapshot = self.state
+            .restore_env(&args.token)
+            .await
+            .ok_or_else(|| McpError::invalid_params(format!("Snapshot {} not found", args.token), None))?;
+
+        let text = format!("Token: {}\nCWD: {}\nEnv vars: {}", args.token, snapshot.cwd, snapshot.env.len());
+        Ok(CallToolResult::success(vec![Content::text(text)]))
+    }
+
+    /// NuRead - Read a line range of a file
+    ///
+    /// Faster and safer than `nu.exec` with `open` for "show me lines 40-80".
+    ///
+    /// Args:
+    ///   path: File to read (relative to the session CWD)
+    ///   start_line / end_line: 1-based inclusive range (optional, default whole file)
+    ///   max_bytes: Content cap (optional, default 100000)
+    ///
+    /// Returns:
+    ///   {path, content, start_line, end_line, total_lines, truncated}
+    #[tool(
+        name = "nu.read",
+        description = r#"Read a text file, or a range of its lines, directly without running Nushell. Returns the content plus the total line count.
+
+ARGS:
+- path: File to read; relative paths resolve against the session CWD
+- start_line / end_line: 1-based inclusive range (default: whole file)
+- max_bytes: Stop after this many bytes of content (default: 100000, max: 1000000)
+- session: Session whose CWD relative paths use (optional)
+
+NOTES:
+- Binary files (NUL bytes) and non-UTF-8 files are refused instead of dumped
+- When max_bytes cuts the range short, `Truncated: true` is shown; continue with start_line set past the last line returned"#
+    )]
+    pub async fn nu_read(&self, args: Parameters<NuReadArgs>) -> Result<CallToolResult, McpError> {
+        let args = &args.0;
 
-EXTERNAL COMMANDS:
-- Prefix with `^`: `^git status`, `^cargo build`
-- Capture output: `let out = (^git status | complete)
+        let result = self.executor
+            .read_file(&self.state, args)
+            .await
+            .map_err(|e| McpError::invalid_request(format!("read_file failed: {e}"), None))?;
+
+        let truncated_line = if result.truncated { "Truncated: true\n" } else { "" };
+        let text = format!("Path: {}\nLines: {}-{} of {}\n{}\n{}",
+            result.path,
+            result.start_line,
+            result.end_line,
+            result.total_lines,
+            truncated_line,
+            result.content
+        );
+        Ok(CallToolResult::success(vec![Content::text(text)]))
+    }
 
-AVOID BASHISMS - use Nushell native:
-- Instead of `cat`: use `open`
-- Instead of `grep`: use `where` with string operations
-- Instead of `sed/awk`: use `str replace`, `update`, `select`
-- Instead of `find`: use `ls` with filters
-- Instead of `&&`: use `;` for chaining
-- Instead of `|`: use `|` (same, but data is structured)
-- Instead of `$VAR`: use `$env.VAR` or `$var`
-- Instead of `$(cmd)`: use `(cmd)` or `^cmd`
-- Instead of `sort | uniq`: use `lines | uniq` for text, or just `uniq` for lists
+    /// NuWrite - Write a file with exact content
+    ///
+    /// Args:
+    ///   path: File to write (relative to the session CWD)
+    ///   content: Exact text to write
+    ///   mode: create (default), overwrite or append
+    ///   mkdirs: Create missing parent directories (optional)
+    ///
+    /// Returns:
+    ///   {path, mode, bytes_written}
+    #[tool(
+        name = "nu.write",
+        description = r#"Write exact text to a file without building a Nushell `save` pipeline.
 
-OUTPUT FORMATTING:
-- To see stdout: pipe to `print` → `ls | print`
-- To get JSON: pipe to `to json` → `ls | to json | print`
-- To CSV: pipe to `to csv` → `ls | to csv`
-- Truncate large output: `ls | take 50 | to json`
+ARGS:
+- path: File to write; relative paths resolve against the session CWD
+- content: Exact text to write (no trailing newline is added)
+- mode: "create" (default, fails if the file exists), "overwrite" or "append"
+- mkdirs: Create missing parent directories (default: false)
+- session: Session whose CWD relative paths use (optional)
 
-WARNING:
-- Avoid searching in 'target/', '.git/', '.cache/', 'node_modules/', '.venv/' (timeouts/encoding errors)
-- Use `| take N BEFORE | to json` for large results
-- If command times out, search in specific subdirectory instead
-- Quote file paths with spaces: `"my path/file.txt""#
+NOTES:
+- Refused when the server is read-only (NU_READONLY)
+- With NU_APPLY_ROOT set, paths outside that directory are refused
+- Use nu.apply for partial edits of existing files"#
     )]
-    pub async fn nu_exec(&self, args: Parameters<NuExecArgs>) -> Result<CallToolResult, McpError> {
+    pub async fn nu_write(&self, args: Parameters<NuWriteArgs>) -> Result<CallToolResult, McpError> {
         let args = &args.0;
-        let env = args.env.as_ref().unwrap_or(&HashMap::new()).clone();
-
-        // If cwd is explicitly provided, temporarily override the state CWD
-        let state = if let Some(ref provided_cwd) = args.cwd {
-            // Create a temporary state with the provided CWD
-            let temp_state = self.state.clone();
-            temp_state.set_cwd(provided_cwd.clone()).await;
-            temp_state
-        } else {
-            self.state.clone()
-        };
 
-        let result = if args.background {
-            let bg_result = self.executor
-                .exec_background(&state, &args.command, &env)
-                .await
-                .map_err(|e| McpError::invalid_request(format!("exec_background failed: {e}"), None))?;
+        let result = self.executor
+            .write_file(&self.state, args)
+            .await
+            .map_err(|e| McpError::invalid_request(format!("write_file failed: {e}"), None))?;
 
-            format!("Background process started.\nID: {}\nStatus: {}\n{}", bg_result.id, bg_result.status, bg_result.message)
-        } else {
-            let timeout = self.executor.resolve_timeout(args.timeout);
-            let exec_result = self.executor
-                .exec_blocking(&state, &args.command, &env, timeout)
-                .await
-                .map_err(|e| McpError::invalid_request(format!("exec_blocking failed: {e}"), None))?;
+        let text = format!("Path: {}\nMode: {}\nBytes written: {}", result.path, result.mode, result.bytes_written);
+        Ok(CallToolResult::success(vec![Content::text(text)]))
+    }
 
-            format!("Exit code: {}\nTime: {}ms\n\n{}",
-                exec_result.exit_code,
-                exec_result.took_ms,
-                exec_result.output
-            )
-        };
+    /// NuWhich - Resolve an external command via PATH
+    ///
+    /// Args:
+    ///   command: Executable name, e.g. "rg"
+    ///   version: Also report the first line of `--version` (optional)
+    ///   session: Session key (optional, default "default")
+    ///
+    /// Returns:
+    ///   {command, found, path?, version?, version_error?}
+    #[tool(
+        name = "nu.which",
+        description = r#"Check whether an external command is installed and where, without spawning Nushell. Use it to branch on tool availability before calling `^tool` through nu.exec.
 
-        Ok(CallToolResult::success(vec![Content::text(result)]))
+ARGS:
+- command: Executable name, e.g. "rg", "cargo", "docker" (required)
+- version: Also run `<command> --version` and return its first line (default: false)
+- session: Session whose env PATH and working directory are used (default: "default")
+
+NOTES:
+- PATH comes from the session env (nu.env), then NU_DEFAULT_ENV, then the server's environment - the same order nu.exec uses
+- A missing command is a normal result with `Found: no`, not an error
+- The version probe honours NU_ALLOW_COMMANDS / NU_DENY_COMMANDS and gives up after 5 seconds"#
+    )]
+    pub async fn nu_which(&self, args: Parameters<NuWhichArgs>) -> Result<CallToolResult, McpError> {
+        let args = &args.0;
+
+        let result = self.executor
+            .which(&self.state, args)
+            .await
+            .map_err(|e| McpError::invalid_request(format!("which failed: {e}"), None))?;
+
+        let mut text = format!("Command: {}\nFound: {}", result.command, if result.found { "yes" } else { "no" });
+        if let Some(ref path) = result.path {
+            text.push_str(&format!("\nPath: {}", path));
+        }
+        if let Some(ref version) = result.version {
+            text.push_str(&format!("\nVersion: {}", version));
+        }
+        if let Some(ref error) = result.version_error {
+            text.push_str(&format!("\nVersion: unavailable ({})", error));
+        }
+        Ok(CallToolResult::success(vec![Content::text(text)]))
     }
 
-    /// NuOutput - Read output from background process
+    /// NuGrep - Search file contents without spawning Nushell
     ///
-    /// Use this to get output from processes started with background=true.
+    /// Use this instead of `ls **/* | where` pipelines, which time out in large trees.
     ///
     /// Args:
-    ///   id: Job ID from NuExec
+    ///   pattern: Regular expression
+    ///   path: File or directory (optional, default the session CWD)
+    ///   glob: Path filter such as "*.rs" (optional)
+    ///   max_results: Max matching lines (optional, default 100, max 1000)
+    ///   case_insensitive: Ignore case (optional)
     ///
     /// Returns:
-    ///   {id, status, output, exit_code?, took_secs?}
+    ///   {pattern, path, matches: [{file, line_number, line}], files_searched, truncated}
     #[tool(
-        name = "nu.output",
-        description = r#"Retrieves output from a running or completed background process started via `nu.exec`.
+        name = "nu.grep",
+        description = r#"Search file contents with a regular expression, walking directories the way ripgrep does. Fast and bounded, so it is safe on large repositories.
+
+ARGS:
+- pattern: Regular expression in Rust regex syntax, e.g. "fn \\w+_test" (required)
+- path: File or directory to search; relative paths resolve against the session CWD (default: the session CWD)
+- glob: Only search matching paths, e.g. "*.rs", "src/**/*.ts", or "!*.lock" to exclude
+- max_results: Stop after this many matching lines (default: 100, max: 1000)
+- case_insensitive: Ignore case (default: false)
+- session: Session whose CWD relative paths use (optional)
 
-Returns current buffer snapshot immediately. Output includes stdout with stderr appended (marked with [stderr] if present)."#
+NOTES:
+- .gitignore/.ignore rules apply (even outside a git repo) and hidden files and directories are skipped, so target/, node_modules/ and .git/ are normally left out
+- Binary files and files over 10 MB are skipped; lines longer than 500 characters are cut short
+- `truncated: true` means more matches exist; narrow path or glob, or raise max_results"#
     )]
-    pub async fn nu_output(&self, args: Parameters<NuOutputArgs>) -> Result<CallToolResult, McpError> {
+    pub async fn nu_grep(&self, args: Parameters<NuGrepArgs>) -> Result<CallToolResult, McpError> {
         let args = &args.0;
 
         let result = self.executor
-            .read_output(&self.state, &args.id)
+            .grep(&self.state, args)
             .await
-            .map_err(|e| McpError::invalid_request(format!("read_output failed: {e}"), None))?;
-
-        let text = format!("ID: {}\nStatus: {}\nRunning for: {}s\nExit code: {}\n\n{}",
-            result.id,
-            result.status,
-            result.took_secs,
-            result.exit_code.map(|c| c.to_string()).unwrap_or_else(|| "running".to_string()),
-            result.output
-        );
+            .map_err(|e| McpError::invalid_request(format!("grep failed: {e}"), None))?;
 
-        Ok(CallToolResult::success(vec![Content::text(text)]))
+        let value = serde_json::to_value(&result)
+            .map_err(|e| McpError::internal_error(format!("Failed to serialize matches: {e}"), None))?;
+        Ok(CallToolResult::structured(value))
     }
 
-    /// NuKill - Kill a background process
+    /// NuFind - List files and directories without spawning Nushell
     ///
-    /// Use this to terminate a running background process.
+    /// Use this instead of recursive `ls` globs, which hang on target/ and node_modules/.
     ///
     /// Args:
-    ///   id: Job ID to kill
+    ///   path: Directory (optional, default the session CWD)
+    ///   glob: Path filter such as "*.rs" (optional)
+    ///   type: "file", "dir" or "symlink" (optional)
+    ///   max_results: Max entries (optional, default 200, max 5000)
+    ///   hidden / no_ignore: Include hidden or gitignored entries (optional)
     ///
     /// Returns:
-    ///   {id, status, command}
+    ///   {path, entries: [{path, type, size?, modified?}], truncated}
     #[tool(
-        name = "nu.kill",
-        description = r#"Terminate a running background process by its job ID to release system resources."#
+        name = "nu.find",
+        description = r#"Find files and directories under a path, walking the tree the way ripgrep does. Bounded, so it never hangs on large build or dependency directories.
+
+ARGS:
+- path: Directory to search; relative paths resolve against the session CWD (default: the session CWD)
+- glob: Only return matching paths, e.g. "*.rs", "**/Cargo.toml", or "!*.lock" to exclude
+- type: "file", "dir" or "symlink" (default: all)
+- max_results: Stop after this many entries (default: 200, max: 5000)
+- hidden: Include hidden files and directories (default: false)
+- no_ignore: Also return paths excluded by .gitignore/.ignore files (default: false)
+- session: Session whose CWD relative paths use (optional)
+
+NOTES:
+- By default gitignored and hidden entries are skipped, so target/, node_modules/ and .git/ are normally left out
+- Each entry has its type and RFC 3339 modification time; files also have their size in bytes
+- Entries are sorted by name within each directory; `truncated: true` means the walk stopped at max_results"#
     )]
-    pub async fn nu_kill(&self, args: Parameters<NuKillArgs>) -> Result<CallToolResult, McpError> {
+    pub async fn nu_find(&self, args: Parameters<NuFindArgs>) -> Result<CallToolResult, McpError> {
         let args = &args.0;
 
         let result = self.executor
-            .kill_process(&self.state, &args.id)
+            .find(&self.state, args)
             .await
-            .map_err(|e| McpError::invalid_request(format!("kill_process failed: {e}"), None))?;
+            .map_err(|e| McpError::invalid_request(format!("find failed: {e}"), None))?;
 
-        let text = format!("ID: {}\nStatus: {}\nCommand: {}", result.id, result.status, result.command);
-        Ok(CallToolResult::success(vec![Content::text(text)]))
+        let value = serde_json::to_value(&result)
+            .map_err(|e| McpError::internal_error(format!("Failed to serialize entries: {e}"), None))?;
+        Ok(CallToolResult::structured(value))
     }
 
     /// NuApply - Apply code edits via OpenAI-compatible API
@@ -239,14 +1279,22 @@ Returns current buffer snapshot immediately. Output includes stdout with stderr
     /// It is much faster and more reliable than standard Edit.
     ///
     /// Supports any OpenAI-compatible provider: MorphLLM (default), Ollama, vLLM, DeepSeek, etc.
-    /// Configure via environment variables: APPLY_API_URL, APPLY_API_KEY, APPLY_MODEL.
+    /// Configure via environment variables: APPLY_API_URL, APPLY_API_KEY, APPLY_MODEL, APPLY_SYSTEM_PROMPT, APPLY_RETRIES.
     ///
     /// NOTE: Requires APPLY_API_KEY (or 'ollama' for local) and APPLY_API_URL to be configured.
+    /// Set APPLY_VERIFY_SYNTAX=1 to reject edits that leave JSON/TOML/YAML files unparseable.
+    /// Set NU_APPLY_ROOT to confine edits to one directory tree.
     ///
     /// Args:
     ///   path: Absolute path to file to edit
     ///   instructions: What to change
     ///   code_edit: Code with `// ... existing code ...` markers
+    ///   confirm_large_change: Allow edits that remove more than half of the file (optional)
+    ///   start_line / end_line / replacement: Replace a 1-based inclusive line range directly (no API call)
+    ///   dry_run: Return a unified diff instead of writing (optional)
+    ///   stage: Hold the edit and return its diff plus a token for nu.apply_confirm (optional)
+    ///   marker: Existing-code marker (optional, inferred from the file extension)
+    ///   stream: Stream the completion from the provider (default: true)
     ///
     /// Returns:
     ///   {path, status, message}
@@ -256,7 +1304,7 @@ Returns current buffer snapshot immediately. Output includes stdout with stderr
     ///   code_edit: "// ... existing code ...\n\nfn new_function() { }\n\n// ... existing code ..."
     #[tool(
         name = "nu.apply",
-        description = r#"Use this tool to edit existing files by showing only the changed lines.
+        description = r##"Use this tool to edit existing files by showing only the changed lines.
 
 Use "// ... existing code ..." to represent unchanged code blocks. Include just enough surrounding context to locate each edit precisely.
 
@@ -272,15 +1320,90 @@ Rules:
 - Include minimal context around edits for disambiguation
 - Preserve exact indentation
 - For deletions: show context before and after, omit the deleted lines
-- Batch multiple edits to the same file in one call"#
+- Batch multiple edits to the same file in one call
+- Write the marker in the file's comment syntax: "# ... existing code ..." for Python/shell/TOML/YAML, "-- ... existing code ..." for Lua/SQL, "<!-- ... existing code ... -->" for HTML/XML/Markdown. It is inferred from the extension (pass marker to override), and "// ... existing code ..." is translated automatically
+- path must be absolute (and inside NU_APPLY_ROOT when the server sets it)
+- Edits that shrink the file by more than 50% are rejected unless confirm_large_change=true
+- If the file changes on disk while the edit is being computed, the call fails with a conflict instead of overwriting it
+- The completion is streamed from the provider by default; pass stream=false for providers that misbehave with SSE
+- dry_run=true runs the full edit but returns a unified diff (status "dry_run") instead of writing
+- stage=true also returns the diff (status "staged") but keeps the edit with a token; nu.apply_confirm writes it after review
+
+LINE-RANGE MODE:
+- When the exact lines are known, pass start_line, end_line and replacement instead of instructions/code_edit
+- Lines are 1-based and inclusive; the range must lie within the file
+- Applied directly without the model; an empty replacement deletes the lines
+- The 50% shrink check applies here too: pass confirm_large_change=true to delete most of a file"##
     )]
     pub async fn nu_apply(&self, args: Parameters<NuApplyArgs>) -> Result<CallToolResult, McpError> {
         let args = &args.0;
+        let options = ApplyOptions::from_args(args);
+        if options.stage && options.dry_run {
+            return Err(McpError::invalid_params("stage and dry_run cannot be combined", None));
+        }
+
+        let result = if let Some(start_line) = args.start_line {
+            let replacement = args.replacement.as_deref()
+                .ok_or_else(|| McpError::invalid_params("replacement is required with start_line", None))?;
+            self.executor
+                .apply_line_range(&self.state, &args.path, start_line, args.end_line.unwrap_or(start_line), replacement, &options)
+                .await
+                .map_err(|e| McpError::invalid_request(format!("apply_line_range failed: {e}"), None))?
+        } else {
+            let (Some(instructions), Some(code_edit)) = (args.instructions.as_deref(), args.code_edit.as_deref()) else {
+                return Err(McpError::invalid_params("instructions and code_edit are required (or use start_line + replacement)", None));
+            };
+            self.executor
+                .apply_file(&self.state, &args.path, instructions, code_edit, &options)
+                .await
+                .map_err(|e| McpError::invalid_request(format!("apply_file failed: {e}"), None))?
+        };
+
+        let text = format!("Path: {}\nStatus: {}\n{}", result.path, result.status, result.message);
+        Ok(CallToolResult::success(vec![Content::text(text)]))
+    }
+
+    /// NuApplyConfirm - Write an edit staged by nu.apply
+    ///
+    /// Args:
+    ///   token: Token from nu.apply with stage=true
+    ///
+    /// Returns:
+    ///   {path, status, message}
+    #[tool(
+        name = "nu.apply_confirm",
+        description = r#"Write an edit that nu.apply computed with stage=true, after its diff was reviewed. Tokens work once and expire after NU_APPLY_STAGE_TTL_SECS (default 600). The write is refused if the file changed since the edit was staged; stage it again in that case."#
+    )]
+    pub async fn nu_apply_confirm(&self, args: Parameters<NuApplyConfirmArgs>) -> Result<CallToolResult, McpError> {
+        let args = &args.0;
+
+        let result = self.executor
+            .confirm_apply(&self.state, &args.token)
+            .await
+            .map_err(|e| McpError::invalid_request(format!("confirm_apply failed: {e}"), None))?;
+
+        let text = format!("Path: {}\nStatus: {}\n{}", result.path, result.status, result.message);
+        Ok(CallToolResult::success(vec![Content::text(text)]))
+    }
+
+    /// NuRestore - Roll a file back to the backup kept by nu.apply
+    ///
+    /// Args:
+    ///   path: File to restore from `{path}.bak`
+    ///
+    /// Returns:
+    ///   {path, status, message}
+    #[tool(
+        name = "nu.restore",
+        description = r#"Restore a file from the {path}.bak backup left by nu.apply. Backups are only kept after a successful apply when the server runs with NU_APPLY_KEEP_BACKUP=1. Fails if the backup is missing or empty. The backup itself is left in place."#
+    )]
+    pub async fn nu_restore(&self, args: Parameters<NuRestoreArgs>) -> Result<CallToolResult, McpError> {
+        let args = &args.0;
 
         let result = self.executor
-            .apply_file(&args.path, &args.instructions, &args.code_edit)
+            .restore_backup(&args.path)
             .await
-            .map_err(|e| McpError::invalid_request(format!("apply_file failed: {e}"), None))?;
+            .map_err(|e| McpError::invalid_request(format!("restore_backup failed: {e}"), None))?;
 
         let text = format!("Path: {}\nStatus: {}\n{}", result.path, result.status, result.message);
         Ok(CallToolResult::success(vec![Content::text(text)]))
@@ -298,9 +1421,17 @@ Rules:
     ///   category: Search category (general, cargo, packages, it, repos, skills, etc.)
     ///   limit: Max results to return (default: 10)
     ///   engines: Specific engines to use (e.g., "npm,pypi")
+    ///   site: Restrict results to one domain (e.g., "docs.rs")
+    ///   verbose: Include ranking positions and parsed URL parts (default: false)
+    ///   group_by_engine: Group results under the engine that returned them (default: false)
+    ///   page: Result page, starting at 1 (default: 1)
+    ///   time_range: Restrict to day/week/month/year (optional)
+    ///   safesearch: 0 off, 1 moderate, 2 strict (optional)
+    ///   language: Result language tag, e.g. "en" or "de" (optional)
+    ///   output_format: "text" (default) or "json" (optional)
     ///
     /// Returns:
-    ///   {query, results: [{title, url, content, engine, category}], total, returned, answers, infoboxes, suggestions}
+    ///   {query, results: [{title, url, content, engine, engines, category, score}], total, page, returned, answers, infoboxes, suggestions, unresponsive_engines, errors}
     ///
     /// Examples:
     ///   query: "tokio" category: "cargo" -> Search Rust crates
@@ -344,15 +1475,20 @@ USAGE EXAMPLES:
 6. General web: query="latest rust news" category="general"
 7. Multi-package: query="http client" category="packages"
 8. Multiple engines: query="web framework" engines="npm,crate,composer"
+9. Single domain: query="serde derive" site="docs.rs"
+10. Recent news: query="rust release" category="news" time_range="week"
 
 RESPONSE STRUCTURE:
 - query: The search query
-- results: Array of {title, url, content, engine, category, score}
+- results: Array of {title, url, content, engine, engines, category, score}, best score first, one entry per URL
 - total: Total results available
+- page: Page of results returned
 - returned: Number of results returned
 - answers: Direct answers/infoboxes from SearXNG (e.g., calculators, conversions)
 - infoboxes: Knowledge panels with structured information
 - suggestions: Search query suggestions
+- unresponsive_engines: Engines that failed or timed out, with the reason
+- errors: Other errors reported by SearXNG
 
 ANSWERS/INFOBOXES:
 - SearXNG returns direct answers for factual queries
@@ -364,38 +1500,90 @@ KNOWN ISSUES:
 - PyPI search takes 1-2 seconds: Loading package index from Simple API
 - Rate limiting: SearXNG may rate-limit if too many requests in quick succession
 - Some engines may be unresponsive: Check unresponsive_engines in response
+- Paging depends on the engines: some ignore page and repeat page 1, others return fewer results on later pages
 
 ARGS:
 - query: Search query string (required)
 - category: Search category (default: general)
 - limit: Max results to return (default: 10)
-- engines: Specific engines to use (comma-separated, e.g., "npm,pypi")"#
+- engines: Specific engines to use (comma-separated, e.g., "npm,pypi")
+- site: Restrict results to a single domain (e.g., "docs.rs", "github.com")
+- verbose: Include per-result ranking positions and parsed URL components (default: false)
+- group_by_engine: Group results under the engine that returned them, to compare engine coverage (default: false)
+- page: Result page, starting at 1; combine with limit to walk past the first page (default: 1)
+- time_range: Only results from the last day, week, month or year (optional)
+- safesearch: 0 off, 1 moderate, 2 strict (default: instance setting)
+- language: Result language tag such as "en", "de", "pt-BR"; not every engine honours it (default: instance setting)
+- output_format: "text" (default) or "json" to get the full result as JSON, with answers and infoboxes exactly as SearXNG returned them"#
     )]
     pub async fn nu_search(&self, args: Parameters<NuSearchArgs>) -> Result<CallToolResult, McpError> {
         let args = &args.0;
 
+        let json_mode = match args.output_format.as_deref() {
+            None | Some("text") => false,
+            Some("json") => true,
+            Some(other) => {
+                return Err(McpError::invalid_params(format!("Unknown output_format '{}': expected text or json", other), None));
+            }
+        };
+
         let result = self.executor
             .search(args)
             .await
             .map_err(|e| McpError::invalid_request(format!("search failed: {e}"), None))?;
 
+        if json_mode {
+            let value = serde_json::to_value(&result)
+                .map_err(|e| McpError::internal_error(format!("Failed to serialize search result: {e}"), None))?;
+            return Ok(CallToolResult::structured(value));
+        }
+
         // Format as plain text for better readability
-        let mut text = format!("Query: \"{}\" | Category: {} | Found: {} results | Showing: {}\n\n",
+        let mut text = format!("Query: \"{}\" | Category: {} | Found: {} results | Showing: {}{}\n\n",
             result.query,
             args.category,
             result.total,
-            result.returned
+            result.returned,
+            if result.page > 1 { format!(" | Page: {}", result.page) } else { String::new() }
         );
 
-        // Add results
-        for (i, item) in result.results.iter().enumerate() {
-            text.push_str(&format!("[{}] {}\n", i + 1, item.title));
+        let format_item = |text: &mut String, n: usize, item: &SearchResultItem| {
+            text.push_str(&format!("[{}] {}\n", n, item.title));
             text.push_str(&format!("    URL: {}\n", item.url));
-            text.push_str(&format!("    Engine: {}\n", item.engine));
+            if item.engines.len() > 1 {
+                text.push_str(&format!("    Engines: {}\n", item.engines.join(", ")));
+            } else {
+                text.push_str(&format!("    Engine: {}\n", item.engine));
+            }
+            if item.score > 0.0 {
+                text.push_str(&format!("    Score: {:.2}\n", item.score));
+            }
+            if let Some(ref positions) = item.positions {
+                let positions: Vec<String> = positions.iter().map(|p| p.to_string()).collect();
+                text.push_str(&format!("    Positions: {}\n", positions.join(", ")));
+            }
+            if let Some(ref parsed) = item.parsed_url {
+                text.push_str(&format!("    Parsed URL: scheme={} host={} path={} query={} fragment={}\n",
+                    parsed.scheme, parsed.host, parsed.path, parsed.query, parsed.fragment));
+            }
             if !item.content.is_empty() {
                 text.push_str(&format!("    Content: {}\n", item.content));
             }
             text.push('\n');
+        };
+
+        // Add results, either per engine or as one ranked list
+        if let Some(ref groups) = result.by_engine {
+            for (engine, items) in groups {
+                text.push_str(&format!("== {} ({} results)\n\n", engine, items.len()));
+                for (i, item) in items.iter().enumerate() {
+                    format_item(&mut text, i + 1, item);
+                }
+            }
+        } else {
+            for (i, item) in result.results.iter().enumerate() {
+                format_item(&mut text, i + 1, item);
+            }
         }
 
         // Add answers if any
@@ -424,21 +1612,89 @@ ARGS:
             }
         }
 
+        // Flag incomplete results so they are not mistaken for the full picture
+        if !result.unresponsive_engines.is_empty() {
+            text.push_str("\n** Unresponsive engines (results may be incomplete):\n");
+            for engine in &result.unresponsive_engines {
+                text.push_str(&format!("    - {}\n", engine));
+            }
+        }
+        if !result.errors.is_empty() {
+            text.push_str("\n** Errors:\n");
+            for err in &result.errors {
+                text.push_str(&format!("    - {}\n", err));
+            }
+        }
+
         Ok(CallToolResult::success(vec![Content::text(text)]))
     }
 
+    /// NuHttp - Raw HTTP request for API testing
+    ///
+    /// Args:
+    ///   url: URL to request
+    ///   method: HTTP method (default: GET)
+    ///   headers: Request headers as key-value pairs (optional)
+    ///   body: Request body (optional)
+    ///   timeout / retries / retry_delay_ms / max_bytes / max_redirects / proxy: Same as nu.fetch
+    ///
+    /// Returns:
+    ///   {method, url, final_url, status, headers, body, body_bytes, truncated, attempts, took_ms}
+    #[tool(
+        name = "nu.http",
+        description = r#"Send a raw HTTP request and get back the status, every response header and the unconverted body as structured JSON. Use it for API testing; use nu.fetch to read web pages.
+
+ARGS:
+- url: URL to request (required)
+- method: GET, POST, PUT, PATCH, DELETE, HEAD, OPTIONS (default: GET)
+- headers: Request headers, e.g. {"Authorization": "Bearer ...", "If-None-Match": "\"abc\""}
+- body: Request body; JSON bodies get Content-Type application/json unless you set one
+- timeout: Seconds (default: 30)
+- retries / retry_delay_ms: Retry connection errors and 429/5xx with backoff (default: 0 / 500)
+- max_bytes: Body cap (default: NU_FETCH_MAX_BYTES)
+- max_redirects: Redirects to follow; 0 returns the 3xx response itself (default: 10)
+- proxy: Proxy URL for this request
+
+NOTES:
+- headers is keyed by lowercase name; repeated headers such as set-cookie are arrays
+- Nothing is converted or cached, and no browser User-Agent is faked (nu-mcp/<version> unless you set one)
+- A 4xx/5xx status is a normal result, not a tool error"#
+    )]
+    pub async fn nu_http(&self, args: Parameters<NuHttpArgs>) -> Result<CallToolResult, McpError> {
+        let args = &args.0;
+
+        let result = self.executor
+            .http(args)
+            .await
+            .map_err(|e| McpError::invalid_request(format!("http failed: {e}"), None))?;
+
+        let value = serde_json::to_value(&result)
+            .map_err(|e| McpError::internal_error(format!("Failed to serialize HTTP response: {e}"), None))?;
+        Ok(CallToolResult::structured(value))
+    }
+
     /// NuFetch - Fetch web content with format conversion
     ///
     /// Use this to fetch and convert web content (HTML to Markdown, JSON as-is, etc.).
     ///
     /// Args:
     ///   url: URL to fetch
-    ///   format: Response format (auto/json/markdown/text, default: auto)
+    ///   format: Response format (auto/markdown/readability/text/tables/links, default: auto)
     ///   headers: Optional HTTP headers as key-value pairs
     ///   timeout: Request timeout in seconds (default: 30)
+    ///   replay: Serve from a recorded exchange in FETCH_RECORD_DIR (default: false)
+    ///   to_resource: Store content as an MCP resource, return URI + preview (default: false)
+    ///   extract_links: Also list all page links as absolute URLs (default: false)
+    ///   retries: Extra attempts on connection errors and 429/5xx (default: 0)
+    ///   retry_delay_ms: Base backoff delay in milliseconds (default: 500)
+    ///   method: HTTP method (default: GET)
+    ///   body: Request body, JSON bodies get application/json automatically
+    ///   max_bytes: Response size cap (default: NU_FETCH_MAX_BYTES, 10MB)
+    ///   max_redirects: Redirects to follow, 0 returns the 3xx as-is (default: 10)
+    ///   proxy: Proxy URL for this request (optional)
     ///
     /// Returns:
-    ///   {url, status, content_type, content, format, error?}
+    ///   {url, status, content_type, content, format, error?, likely_js_rendered, attempts, truncated, final_url, location?, cached}
     ///
     /// Examples:
     ///   url: "https://example.com" format: "markdown" -> Fetch HTML and convert to Markdown
@@ -450,6 +1706,10 @@ ARGS:
 
 FORMAT CONVERSION:
 - HTML → Markdown (automatic)
+- format="readability" → main article only as Markdown (nav/footer stripped; falls back to the full page)
+- format="text" → raw body, no conversion
+- format="tables" → every HTML <table> as a JSON array of records (header row → keys, else column0, column1, ...)
+- format="links" → JSON list of {text, href}, relative URLs resolved against the page
 - JSON/Text → As-is
 
 BROWSER FINGERPRINTING:
@@ -460,19 +1720,31 @@ USAGE EXAMPLES:
 1. Fetch webpage: url="https://example.com"
 2. Fetch API: url="https://api.github.com/users/octocat"
 3. Custom headers: url="https://httpbin.org/headers" headers={"Authorization": "Bearer token"}
+4. POST JSON: url="https://httpbin.org/post" method="POST" body="{\"name\": \"nu\"}"
 
 RESPONSE STRUCTURE:
 - url: The fetched URL
 - status: HTTP status code (200, 404, etc.)
 - content_type: Response content-type header
 - content: Response content (HTML converted to Markdown)
-- format: Actual format returned (markdown/text)
+- format: Actual format returned (markdown/readability/text/tables/links)
 - error: Error message if status >= 400, null otherwise
+- likely_js_rendered: true when the page looks like a JavaScript app shell (a Note line is added)
 
 NOTES:
 - HTML to Markdown conversion uses html2md library
+- JavaScript is not executed: near-empty results for app shells are flagged so you can switch to a headless browser
 - Timeout prevents hanging (default: 30 seconds)
-- Custom User-Agent can be provided via headers"#
+- Custom User-Agent can be provided via headers
+- When FETCH_RECORD_DIR is set, each fetch is recorded there; replay=true serves the recorded response offline
+- to_resource=true stores large pages as an MCP resource (nu-mcp://fetch/<id>) and returns a preview
+- extract_links=true appends a deduplicated list of absolute URLs from every <a href> on an HTML page
+- Bodies over max_bytes (default NU_FETCH_MAX_BYTES, 10MB) are cut off with a truncation marker; a larger Content-Length fails before downloading
+- max_redirects limits redirect following; max_redirects=0 returns the 3xx status with its Location
+- proxy routes this request through a proxy (overrides NU_HTTP_PROXY/NU_HTTPS_PROXY); hosts in NO_PROXY are still reached directly
+- GET/HEAD results are cached in memory for NU_FETCH_CACHE_TTL_SECS (default 300, 0 disables); hits show "Cached: true". Responses with Cache-Control: no-store or status >= 400 are not cached
+- With NU_RESPECT_ROBOTS=1, URLs disallowed by the site's robots.txt for the request's User-Agent are refused
+- retries=N retries connection errors and 429/5xx responses with exponential backoff (base retry_delay_ms, default 500), honouring Retry-After"#
     )]
     pub async fn nu_fetch(&self, args: Parameters<NuFetchArgs>) -> Result<CallToolResult, McpError> {
         let args = &args.0;
@@ -482,14 +1754,68 @@ NOTES:
             .await
             .map_err(|e| McpError::invalid_request(format!("fetch failed: {e}"), None))?;
 
-        let mut text = format!("URL: {}\nStatus: {}\nContent-Type: {}\nFormat: {}\n\n{}",
+        // Large documents can be parked in a resource instead of inlined
+        let body = if args.to_resource.unwrap_or(false) {
+            let resource = self.executor
+                .store_fetch_resource(&self.state, &result)
+                .await
+                .map_err(|e| McpError::internal_error(format!("store_fetch_resource failed: {e}"), None))?;
+            let preview: String = result.content.chars().take(1000).collect();
+            format!("Resource: {}\nSize: {} bytes\n\nPreview:\n{}{}",
+                resource.uri,
+                resource.size,
+                preview,
+                if preview.len() < result.content.len() { "\n..." } else { "" }
+            )
+        } else {
+            result.content.clone()
+        };
+
+        let mut extra_lines = if result.attempts > 1 {
+            format!("Attempts: {}\n", result.attempts)
+        } else {
+            String::new()
+        };
+        if result.cached {
+            extra_lines.push_str("Cached: true\n");
+        }
+        if result.truncated {
+            extra_lines.push_str("Truncated: true\n");
+        }
+        // UTF-8 is the unremarkable case
+        if let Some(charset) = result.charset.as_ref().filter(|c| *c != "UTF-8") {
+            extra_lines.push_str(&format!("Charset: {}\n", charset));
+        }
+        // Only worth showing when a redirect actually happened (compare normalized URLs)
+        let redirected = |final_url: &&String| {
+            reqwest::Url::parse(&result.url).map_or(true, |url| url.as_str() != final_url.as_str())
+        };
+        if let Some(final_url) = result.final_url.as_ref().filter(redirected) {
+            extra_lines.push_str(&format!("Final-URL: {}\n", final_url));
+        }
+        if let Some(ref location) = result.location {
+            extra_lines.push_str(&format!("Location: {}\n", location));
+        }
+        let mut text = format!("URL: {}\nStatus: {}\nContent-Type: {}\nFormat: {}\n{}\n{}",
             result.url,
             result.status,
             result.content_type,
             result.format,
-            result.content
+            extra_lines,
+            body
         );
 
+        if let Some(ref links) = result.links {
+            text.push_str(&format!("\n\nLinks ({}):\n", links.len()));
+            for link in links {
+                text.push_str(&format!("- {}\n", link));
+            }
+        }
+
+        if result.likely_js_rendered {
+            text.push_str("\nNote: This page likely needs JavaScript to render; the content above may be incomplete. Use a headless browser for the full page.");
+        }
+
         if let Some(err) = result.error {
             text.push_str(&format!("\nError: {}", err));
         }
@@ -498,10 +1824,55 @@ NOTES:
     }
 }
 
-#[tool_handler]
 impl rmcp::ServerHandler for NuServer {
+    async fn call_tool(
+        &self,
+        request: CallToolRequestParam,
+        context: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        let Some(audit) = self.audit.clone() else {
+            return self.tool_router.call(ToolCallContext::new(self, request, context)).await;
+        };
+
+        let start = std::time::Instant::now();
+        let tool = request.name.to_string();
+        let args = audit.select_args(request.arguments.as_ref());
+        let result = self.tool_router.call(ToolCallContext::new(self, request, context)).await;
+        let (status, exit_code, error) = match result {
+            Ok(ref call) => (
+                if call.is_error == Some(true) { "tool_error" } else { "ok" },
+                reported_exit_code(call),
+                None,
+            ),
+            Err(ref e) => ("error", None, Some(e.message.to_string())),
+        };
+        audit.record(AuditEntry {
+            timestamp: time::OffsetDateTime::now_utc()
+                .format(&time::format_description::well_known::Rfc3339)
+                .unwrap_or_default(),
+            tool,
+            args,
+            status: status.to_string(),
+            exit_code,
+            error,
+            duration_ms: start.elapsed().as_millis(),
+        }).await;
+        result
+    }
+
+    async fn list_tools(
+        &self,
+        _request: Option<PaginatedRequestParam>,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListToolsResult, McpError> {
+        Ok(ListToolsResult::with_all_items(self.tool_router.list_all()))
+    }
+
     fn get_info(&self) -> ServerInfo {
-        let instructions = "Nushell execution server with 6 tools: nu.exec (run commands), nu.output (read bg process output), nu.kill (kill bg process), nu.apply (fast code edits), nu.search (web/packages search), nu.fetch (fetch web content).";
+        let mut instructions = "Nushell execution server with 27 tools: nu.exec (run commands), nu.script (run multi-line scripts), nu.output (read bg process output), nu.wait (wait for bg process to finish), nu.kill (kill bg process), nu.killall (kill all bg processes), nu.signal (signal bg process), nu.detach (stop monitoring bg process), nu.ps (list system processes), nu.stats (CPU/memory of bg jobs), nu.cd / nu.pushd / nu.popd (change directory), nu.env (get/set/unset session env vars), nu.env_snapshot / nu.env_restore (checkpoint and roll back CWD + session env), nu.read (read file line ranges), nu.write (create/overwrite/append files), nu.which (locate external commands), nu.grep (search file contents, .gitignore-aware), nu.find (list files with size and mtime, .gitignore-aware), nu.apply (fast code edits), nu.apply_confirm (write a staged edit), nu.restore (undo an apply from its backup), nu.search (web/packages search), nu.fetch (fetch web content), nu.http (raw HTTP requests with headers).".to_string();
+        if let Some(ref version) = self.executor.nu_version {
+            instructions.push_str(&format!(" Nushell version: {version}."));
+        }
 
         ServerInfo {
             protocol_version: rmcp::model::ProtocolVersion::V_2024_11_05,
@@ -512,10 +1883,108 @@ impl rmcp::ServerHandler for NuServer {
                 icons: None,
                 website_url: None,
             },
-            capabilities: ServerCapabilities::builder().enable_tools().build(),
-            instructions: Some(instructions.to_string()),
+            capabilities: ServerCapabilities::builder().enable_tools().enable_resources().build(),
+            instructions: Some(instructions),
         }
     }
+
+    async fn list_resources(
+        &self,
+        _request: Option<PaginatedRequestParam>,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListResourcesResult, McpError> {
+        let resources = self.state
+            .list_resources()
+            .await
+            .into_iter()
+            .map(|entry| {
+                let mut resource = RawResource::new(entry.uri, entry.name);
+                resource.mime_type = Some(entry.mime_type);
+                resource.size = u32::try_from(entry.size).ok();
+                resource.no_annotation()
+            })
+            .collect();
+
+        Ok(ListResourcesResult::with_all_items(resources))
+    }
+
+    async fn read_resource(
+        &self,
+        request: ReadResourceRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ReadResourceResult, McpError> {
+        let entry = self.state
+            .get_resource(&request.uri)
+            .await
+            .ok_or_else(|| McpError::resource_not_found(format!("Resource {} not found", request.uri), None))?;
+
+        let text = tokio::fs::read_to_string(&entry.path)
+            .await
+            .map_err(|e| McpError::internal_error(format!("Failed to read resource {}: {e}", entry.uri), None))?;
+
+        Ok(ReadResourceResult {
+            contents: vec![ResourceContents::TextResourceContents {
+                uri: entry.uri,
+                mime_type: Some(entry.mime_type),
+                text,
+                meta: None,
+            }],
+        })
+    }
+}
+
+/// Does the pipeline already end in `to json` (optionally `-r`/`--raw`)?
+fn ends_with_to_json(command: &str) -> bool {
+    let last = command.trim().trim_end_matches(';').rsplit('|').next().unwrap_or_default();
+    let mut words = last.split_whitespace();
+    words.next() == Some("to")
+        && words.next() == Some("json")
+        && words.all(|w| matches!(w, "-r" | "--raw"))
+}
+
+/// Exit code of a command-running tool, from structured content or the "Exit code:" line
+fn reported_exit_code(result: &CallToolResult) -> Option<i64> {
+    if let Some(code) = result.structured_content.as_ref().and_then(|v| v.get("exit_code")).and_then(|v| v.as_i64()) {
+        return Some(code);
+    }
+    result
+        .content
+        .iter()
+        .filter_map(|c| c.as_text())
+        .flat_map(|t| t.text.lines())
+        .find_map(|line| line.strip_prefix("Exit code: ")?.trim().parse().ok())
+}
+
+/// How often a blocking nu.exec reports progress to clients that asked for it
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Send elapsed time and output line count until aborted or the client goes away
+fn spawn_progress_reporter(
+    peer: Peer<RoleServer>,
+    token: ProgressToken,
+    lines_seen: Arc<AtomicUsize>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let start = std::time::Instant::now();
+        loop {
+            tokio::time::sleep(PROGRESS_INTERVAL).await;
+            let elapsed = start.elapsed().as_secs_f64();
+            let param = ProgressNotificationParam {
+                progress_token: token.clone(),
+                progress: elapsed,
+                total: None,
+                message: Some(format!(
+                    "Running for {:.0}s, {} lines of output",
+                    elapsed,
+                    lines_seen.load(Ordering::Relaxed)
+                )),
+            };
+            if let Err(e) = peer.notify_progress(param).await {
+                warn!("Stopping progress notifications: {}", e);
+                break;
+            }
+        }
+    })
 }
 
 #[tokio::main]
@@ -529,6 +1998,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .init();
 
     let server = NuServer::new();
+    if server.executor.nu_version.is_none() {
+        return Err(format!("cannot run '{}'; install Nushell or point NU_PATH at it", server.executor.nu_path).into());
+    }
 
     let service = server
         .serve(stdio())
@@ -538,4 +2010,162 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     info!("nu-mcp server started");
     service.waiting().await?;
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rmcp::service::RunningService;
+    use rmcp::RoleClient;
+
+    type Client = RunningService<RoleClient, ()>;
+
+    /// Server wired to an in-process client; None (test skipped) when Nushell cannot be run,
+    /// unless NU_PATH is set, in which case the test fails.
+    /// The returned server shares its state with the one serving the client.
+    async fn connect() -> Option<(Client, NuServer)> {
+        let server = NuServer::new();
+        if server.executor.nu_version.is_none() {
+            if std::env::var_os("NU_PATH").is_some() {
+                panic!("NU_PATH is set but '{}' cannot be run", server.executor.nu_path);
+            }
+            eprintln!("skipping: nu is not installed (set NU_PATH to require it)");
+            return None;
+        }
+        let (server_io, client_io) = tokio::io::duplex(1 << 16);
+        let handle = server.clone();
+        tokio::spawn(async move {
+            if let Ok(service) = server.serve(server_io).await {
+                let _ = service.waiting().await;
+            }
+        });
+        Some((().serve(client_io).await.unwrap(), handle))
+    }
+
+    async fn call(client: &Client, name: &'static str, arguments: serde_json::Value) -> Result<CallToolResult, rmcp::ServiceError> {
+        client
+            .call_tool(CallToolRequestParam { name: name.into(), arguments: arguments.as_object().cloned() })
+            .await
+    }
+
+    fn text(result: &CallToolResult) -> String {
+        result.content.iter().filter_map(|c| c.as_text().map(|t| t.text.clone())).collect()
+    }
+
+    #[tokio::test]
+    async fn fail_on_nonzero_turns_a_failed_command_into_a_tool_error() {
+        let Some((client, _)) = connect().await else { return };
+
+        let failed = call(&client, "nu.exec", json!({ "command": "print oops; exit 3", "fail_on_nonzero": true })).await.unwrap();
+        assert_eq!(failed.is_error, Some(true));
+        assert!(text(&failed).contains("Exit code: 3"), "{}", text(&failed));
+        assert!(text(&failed).contains("oops"), "{}", text(&failed));
+
+        let plain = call(&client, "nu.exec", json!({ "command": "print oops; exit 3" })).await.unwrap();
+        assert_ne!(plain.is_error, Some(true));
+    }
+
+    #[tokio::test]
+    async fn compact_returns_a_single_summary_line() {
+        let Some((client, _)) = connect().await else { return };
+
+        let result = call(&client, "nu.exec", json!({ "command": "print hello", "compact": true })).await.unwrap();
+        let out = text(&result);
+        assert!(out.starts_with("[exit=0 "), "{}", out);
+        assert!(out.trim_end().ends_with("ms] hello"), "{}", out);
+        assert_eq!(out.trim_end().lines().count(), 1, "{}", out);
+    }
+
+    #[tokio::test]
+    async fn table_output_returns_columns_and_rows() {
+        let Some((client, _)) = connect().await else { return };
+
+        let result = call(&client, "nu.exec", json!({ "command": "ls | select name size", "output_format": "table" })).await.unwrap();
+        let table = result.structured_content.expect("table result is structured");
+        assert_eq!(table["columns"], json!(["name", "size"]), "{}", table);
+        assert!(table["rows"].as_array().is_some_and(|rows| !rows.is_empty()), "{}", table);
+    }
+
+    #[tokio::test]
+    async fn retries_rerun_a_command_until_it_succeeds() {
+        let Some((client, _)) = connect().await else { return };
+        let dir = tempfile::tempdir().unwrap();
+        let counter = dir.path().join("attempts");
+        // Fails on the first two runs, succeeds on the third
+        let command = format!(
+            "let f = '{}'; let n = (if ($f | path exists) {{ open $f | into int }} else {{ 0 }}) + 1; $n | into string | save -f $f; if $n < 3 {{ exit 1 }}; print done",
+            counter.display()
+        );
+
+        let result = call(&client, "nu.exec", json!({ "command": command, "retries": 3 })).await.unwrap();
+        let out = text(&result);
+        assert!(out.contains("Exit code: 0"), "{}", out);
+        assert!(out.contains("Attempts: 3"), "{}", out);
+        assert!(out.contains("done"), "{}", out);
+        assert_eq!(std::fs::read_to_string(&counter).unwrap().trim(), "3");
+    }
+
+    #[tokio::test]
+    async fn rejected_scratch_calls_leave_no_directory_behind() {
+        let Some((client, _)) = connect().await else { return };
+        let scratch_root = std::env::temp_dir().join("nu-mcp");
+        let scratch_dirs = || -> Vec<std::path::PathBuf> {
+            std::fs::read_dir(&scratch_root)
+                .map(|entries| entries.filter_map(|e| e.ok()).map(|e| e.path()).collect())
+                .unwrap_or_default()
+        };
+        let before = scratch_dirs();
+
+        for invalid in [
+            json!({ "command": "ls", "scratch": true, "output_format": "xml" }),
+            json!({ "command": "ls", "scratch": true, "background": true, "as_json": true }),
+            json!({ "command": "ls", "scratch": true, "redact_patterns": ["("] }),
+        ] {
+            assert!(call(&client, "nu.exec", invalid).await.is_err());
+        }
+        let leaked: Vec<_> = scratch_dirs().into_iter().filter(|d| !before.contains(d)).collect();
+        assert!(leaked.is_empty(), "{:?}", leaked);
+    }
+
+    #[tokio::test]
+    async fn scratch_runs_in_its_own_directory() {
+        let Some((client, server)) = connect().await else { return };
+        let session_cwd = std::path::PathBuf::from(server.state.get_cwd(DEFAULT_SESSION).await);
+        let scratch_dir = |result: &CallToolResult, suffix: &str| -> std::path::PathBuf {
+            let text = text(result);
+            let line = text.lines().find_map(|l| l.strip_prefix("Scratch: ")).unwrap_or_else(|| panic!("{}", text));
+            std::path::PathBuf::from(line.strip_suffix(suffix).unwrap_or_else(|| panic!("{}", line)))
+        };
+
+        let args = json!({ "command": "touch made.txt", "scratch": true, "keep_scratch": true });
+        let kept = scratch_dir(&call(&client, "nu.exec", args).await.unwrap(), " (kept)");
+        let made = kept.join("made.txt").exists();
+        std::fs::remove_dir_all(&kept).unwrap();
+        assert!(made, "{}", kept.display());
+        assert!(!session_cwd.join("made.txt").exists());
+
+        let args = json!({ "command": "touch made.txt", "scratch": true, "keep_scratch": false });
+        let removed = scratch_dir(&call(&client, "nu.exec", args).await.unwrap(), " (removed)");
+        assert!(!removed.exists(), "{}", removed.display());
+        assert!(!session_cwd.join("made.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn concurrent_cwd_overrides_stay_isolated() {
+        let Some((client, server)) = connect().await else { return };
+        let session_cwd = server.state.get_cwd(DEFAULT_SESSION).await;
+        let (a, b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (a_path, b_path) = (a.path().display().to_string(), b.path().display().to_string());
+
+        let (in_a, in_b, in_session) = tokio::join!(
+            call(&client, "nu.exec", json!({ "command": "sleep 300ms; pwd", "cwd": a_path })),
+            call(&client, "nu.exec", json!({ "command": "sleep 200ms; pwd", "cwd": b_path })),
+            call(&client, "nu.exec", json!({ "command": "sleep 100ms; pwd" })),
+        );
+        let last_line = |result: &CallToolResult| text(result).trim_end().lines().last().unwrap_or_default().to_string();
+        assert_eq!(last_line(&in_a.unwrap()), a_path);
+        assert_eq!(last_line(&in_b.unwrap()), b_path);
+        assert_eq!(last_line(&in_session.unwrap()), session_cwd);
+        assert_eq!(server.state.get_cwd(DEFAULT_SESSION).await, session_cwd);
+    }
+}
@@ -2,8 +2,9 @@
 
 use std::collections::HashMap;
 use std::path::PathBuf;
-use std::sync::Arc;
-use tokio::sync::Mutex as TokioMutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use tokio::sync::{Mutex as TokioMutex, Notify};
 use tokio::process::Child;
 
 /// Running process metadata with output buffering
@@ -11,7 +12,11 @@ use tokio::process::Child;
 pub struct ProcessInfo {
     /// Child process (wrapped so we can take it while keeping ProcessInfo in map)
     pub child: Arc<TokioMutex<Option<Child>>>,
+    /// OS process ID, kept for signalling while the monitor owns the child
+    pub pid: Option<u32>,
     pub started_at: std::time::Instant,
+    /// When the monitor saw the process finish (set for Completed/Failed)
+    pub completed_at: Arc<TokioMutex<Option<std::time::Instant>>>,
     pub command: String,
     /// Buffered stdout output
     pub stdout_buffer: Arc<TokioMutex<String>>,
@@ -21,6 +26,56 @@ pub struct ProcessInfo {
     pub exit_code: Arc<TokioMutex<Option<i32>>>,
     /// Process status
     pub status: Arc<TokioMutex<ProcessStatus>>,
+    /// Total bytes received on stdout+stderr (monotonic, unaffected by truncation)
+    pub bytes_received: Arc<AtomicUsize>,
+    /// Woken on every buffer append and on completion
+    pub output_notify: Arc<Notify>,
+    /// Sequenced output chunks for ordered streaming
+    pub chunks: Arc<TokioMutex<ChunkLog>>,
+    /// Set once the job is detached; drain tasks stop buffering output
+    pub detached: Arc<AtomicBool>,
+    /// Wakes the monitor so it hands the child back and exits
+    pub detach_notify: Arc<Notify>,
+    /// JSON-lines copy of every output line (`log_file` in nu.exec)
+    pub log_file: Option<PathBuf>,
+}
+
+/// Maximum number of chunks retained per process (older ones are evicted)
+const MAX_CHUNKS: usize = 2000;
+
+/// One sequenced piece of process output
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct OutputChunk {
+    pub seq: u64,
+    /// "stdout", "stderr", or "eof" for the terminal chunk
+    pub stream: &'static str,
+    pub data: String,
+    /// Set on the terminal chunk appended when the process exits
+    #[serde(rename = "final")]
+    pub is_final: bool,
+}
+
+/// Bounded, sequenced log of output chunks
+#[derive(Debug, Default)]
+pub struct ChunkLog {
+    next_seq: u64,
+    chunks: std::collections::VecDeque<OutputChunk>,
+}
+
+impl ChunkLog {
+    /// Append a chunk, assigning the next sequence number (starting at 1)
+    pub fn push(&mut self, stream: &'static str, data: String, is_final: bool) {
+        self.next_seq += 1;
+        if self.chunks.len() >= MAX_CHUNKS {
+            self.chunks.pop_front();
+        }
+        self.chunks.push_back(OutputChunk { seq: self.next_seq, stream, data, is_final });
+    }
+
+    /// Chunks with a sequence number greater than `since`
+    pub fn since(&self, since: u64) -> Vec<OutputChunk> {
+        self.chunks.iter().filter(|c| c.seq > since).cloned().collect()
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
@@ -29,26 +84,56 @@ pub enum ProcessStatus {
     Running,
     Completed,
     Failed,
+    /// Monitoring stopped; the process may still be running
+    Detached,
 }
 
 /// Buffer references for monitor task
 pub struct BufferRefs {
+    pub child: Arc<TokioMutex<Option<Child>>>,
     pub stdout: Arc<TokioMutex<String>>,
     pub stderr: Arc<TokioMutex<String>>,
     pub status: Arc<TokioMutex<ProcessStatus>>,
     pub exit_code: Arc<TokioMutex<Option<i32>>>,
+    pub completed_at: Arc<TokioMutex<Option<std::time::Instant>>>,
+    pub bytes_received: Arc<AtomicUsize>,
+    pub output_notify: Arc<Notify>,
+    pub chunks: Arc<TokioMutex<ChunkLog>>,
+    pub detached: Arc<AtomicBool>,
+    pub detach_notify: Arc<Notify>,
+}
+
+impl BufferRefs {
+    /// Record that `len` bytes were appended and wake long-polling readers
+    pub fn signal_output(&self, len: usize) {
+        self.bytes_received.fetch_add(len, Ordering::SeqCst);
+        self.output_notify.notify_waiters();
+    }
+
+    /// Whether the job has been detached
+    pub fn is_detached(&self) -> bool {
+        self.detached.load(Ordering::SeqCst)
+    }
 }
 
 impl ProcessInfo {
     pub fn new(child: Child, command: String) -> Self {
         Self {
+            pid: child.id(),
             child: Arc::new(TokioMutex::new(Some(child))),
             started_at: std::time::Instant::now(),
+            completed_at: Arc::new(TokioMutex::new(None)),
             command,
             stdout_buffer: Arc::new(TokioMutex::new(String::new())),
             stderr_buffer: Arc::new(TokioMutex::new(String::new())),
             exit_code: Arc::new(TokioMutex::new(None)),
             status: Arc::new(TokioMutex::new(ProcessStatus::Running)),
+            bytes_received: Arc::new(AtomicUsize::new(0)),
+            output_notify: Arc::new(Notify::new()),
+            chunks: Arc::new(TokioMutex::new(ChunkLog::default())),
+            detached: Arc::new(AtomicBool::new(false)),
+            detach_notify: Arc::new(Notify::new()),
+            log_file: None,
         }
     }
 
@@ -58,50 +143,381 @@ impl ProcessInfo {
     }
 }
 
+/// How output buffers behave once they exceed their size limit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BufferMode {
+    /// Keep the beginning and drop what follows (default)
+    Head,
+    /// Keep the beginning and the most recent output, dropping the middle
+    HeadTail {
+        /// Fraction of the limit reserved for the head
+        head_ratio: f64,
+    },
+}
+
+impl BufferMode {
+    /// Read NU_BUFFER_MODE (head|head_tail) and NU_BUFFER_HEAD_RATIO (0.0-1.0, default 0.3) once
+    pub fn from_env() -> Self {
+        static MODE: OnceLock<BufferMode> = OnceLock::new();
+        *MODE.get_or_init(|| match std::env::var("NU_BUFFER_MODE").as_deref() {
+            Ok("head_tail") => {
+                let head_ratio = std::env::var("NU_BUFFER_HEAD_RATIO")
+                    .ok()
+                    .and_then(|r| r.parse::<f64>().ok())
+                    .filter(|r| (0.0..=1.0).contains(r))
+                    .unwrap_or(0.3);
+                BufferMode::HeadTail { head_ratio }
+            }
+            _ => BufferMode::Head,
+        })
+    }
+}
+
+/// Largest index <= `index` that lies on a char boundary
+fn floor_char_boundary(s: &str, index: usize) -> usize {
+    let mut index = index.min(s.len());
+    while !s.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+/// Human-readable byte count, e.g. `512B`, `3.4KB`, `1.2MB`
+pub fn format_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", value, UNITS[unit])
+}
+
+fn truncated_marker(dropped: usize) -> String {
+    format!("\n... <truncated {}> ...", format_bytes(dropped))
+}
+
 /// Push data to buffer with truncation
-pub fn push_truncated(buffer: &mut String, data: &str, max_size: usize) {
-    if buffer.len() + data.len() > max_size {
-        let remaining = max_size.saturating_sub(100);
-        if data.len() > remaining {
-            buffer.push_str(&data[..remaining]);
-            buffer.push_str("\n... <truncated> ...");
-        } else {
-            buffer.truncate(remaining);
-            buffer.push_str(data);
-            buffer.push_str("\n... <truncated> ...");
-        }
+///
+/// `dropped` is the caller's running count of discarded bytes for this buffer; once the limit
+/// is reached further data is counted there and reported in a trailing `... <truncated N> ...` marker.
+pub fn push_truncated(buffer: &mut String, data: &str, max_size: usize, dropped: &mut usize) {
+    if let BufferMode::HeadTail { head_ratio } = BufferMode::from_env() {
+        push_head_tail(buffer, data, max_size, head_ratio, dropped);
+        return;
+    }
+
+    if *dropped > 0 {
+        // Already full: only the marker changes
+        let marker_len = truncated_marker(*dropped).len();
+        buffer.truncate(buffer.len() - marker_len);
+        *dropped += data.len();
     } else {
         buffer.push_str(data);
+        if buffer.len() <= max_size {
+            return;
+        }
+        // Leave room for the marker
+        let cut = floor_char_boundary(buffer, max_size.saturating_sub(100));
+        *dropped += buffer.len() - cut;
+        buffer.truncate(cut);
+    }
+    buffer.push_str(&truncated_marker(*dropped));
+}
+
+/// File-backed content exposed to clients as an MCP resource
+#[derive(Debug, Clone)]
+pub struct ResourceEntry {
+    pub uri: String,
+    pub name: String,
+    pub path: PathBuf,
+    pub mime_type: String,
+    pub size: u64,
+}
+
+const OMITTED_PREFIX: &str = "\n... ";
+const OMITTED_SUFFIX: &str = " bytes omitted ...\n";
+
+/// Push data keeping the head and a rolling tail, with a `... N bytes omitted ...` marker between
+///
+/// `dropped` is the running count of omitted bytes, as in `push_truncated`; the marker shows it.
+fn push_head_tail(buffer: &mut String, data: &str, max_size: usize, head_ratio: f64, dropped: &mut usize) {
+    buffer.push_str(data);
+    if buffer.len() <= max_size {
+        return;
+    }
+
+    // After the first cut the marker for the current count sits right after the head
+    let head_end = floor_char_boundary(buffer, (max_size as f64 * head_ratio) as usize);
+    let tail_start = if *dropped > 0 {
+        head_end + omitted_marker(*dropped).len()
+    } else {
+        head_end
+    };
+
+    // Leave room for the marker itself
+    let marker_reserve = OMITTED_PREFIX.len() + OMITTED_SUFFIX.len() + 20;
+    let tail_budget = max_size.saturating_sub(head_end + marker_reserve);
+    let tail_len = buffer.len() - tail_start;
+    let mut cut = tail_start;
+    if tail_len > tail_budget {
+        cut = tail_start + (tail_len - tail_budget);
+        while !buffer.is_char_boundary(cut) {
+            cut += 1;
+        }
+        *dropped += cut - tail_start;
     }
+
+    let rebuilt = format!("{}{}{}", &buffer[..head_end], omitted_marker(*dropped), &buffer[cut..]);
+    *buffer = rebuilt;
 }
 
+fn omitted_marker(omitted: usize) -> String {
+    format!("{}{}{}", OMITTED_PREFIX, omitted, OMITTED_SUFFIX)
+}
+
+/// Maximum number of environment snapshots kept (oldest evicted first)
+const MAX_SNAPSHOTS: usize = 32;
+
+/// Saved session state (CWD + session environment)
+#[derive(Debug, Clone)]
+pub struct EnvSnapshot {
+    pub cwd: String,
+    pub env: HashMap<String, String>,
+}
+
+/// Maximum number of staged nu.apply edits kept (oldest evicted first)
+const MAX_STAGED_APPLIES: usize = 32;
+
+/// An edit computed by `nu.apply` with `stage`, waiting for `nu.apply_confirm`
+#[derive(Debug, Clone)]
+pub struct StagedApply {
+    pub path: String,
+    /// File content the edit was computed against; confirming fails if the file no longer matches
+    pub original: String,
+    pub updated: String,
+    pub staged_at: std::time::Instant,
+}
+
+/// Background job record written to NU_STATE_FILE so jobs survive a server restart
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PersistedProcess {
+    pub id: String,
+    pub command: String,
+    pub pid: u32,
+    /// Unix timestamp (seconds) when the job started
+    pub started_at: u64,
+}
+
+impl PersistedProcess {
+    /// Whether the PID still belongs to this job (guards against PID reuse)
+    pub fn is_alive(&self) -> bool {
+        use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System, UpdateKind};
+        let pid = Pid::from_u32(self.pid);
+        let mut system = System::new();
+        system.refresh_processes_specifics(
+            ProcessesToUpdate::Some(&[pid]),
+            true,
+            ProcessRefreshKind::nothing().with_cmd(UpdateKind::Always),
+        );
+        // The job runs as `nu -c "<cwd wrapper>; <command>"`, so the command appears in its arguments
+        system.process(pid).is_some_and(|p| {
+            p.cmd().iter().any(|arg| arg.to_string_lossy().contains(&self.command))
+        })
+    }
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Load jobs from a previous run, keeping only those whose process is still alive
+fn load_orphans(path: &std::path::Path) -> HashMap<String, PersistedProcess> {
+    let records: Vec<PersistedProcess> = match std::fs::read_to_string(path) {
+        Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+            tracing::warn!("Ignoring unreadable state file {}: {}", path.display(), e);
+            Vec::new()
+        }),
+        Err(_) => Vec::new(),
+    };
+
+    records
+        .into_iter()
+        .filter(|record| {
+            let alive = record.is_alive();
+            if alive {
+                tracing::info!("Found job {} (pid {}) from a previous run: {}", record.id, record.pid, record.command);
+            }
+            alive
+        })
+        .map(|record| (record.id.clone(), record))
+        .collect()
+}
+
+/// Session key used when a call doesn't name one
+pub const DEFAULT_SESSION: &str = "default";
+
 /// Global application state
 #[derive(Clone)]
 pub struct AppState {
     pub processes: Arc<TokioMutex<HashMap<String, ProcessInfo>>>,
-    pub cwd: Arc<TokioMutex<String>>,
+    /// Working directory per session key; sessions start in `initial_cwd`
+    pub cwds: Arc<TokioMutex<HashMap<String, String>>>,
+    pub initial_cwd: String,
+    /// Directory every session is confined to (NU_ROOT)
+    pub root: Option<PathBuf>,
+    /// pushd/popd directory stack per session key
+    pub dir_stacks: Arc<TokioMutex<HashMap<String, Vec<String>>>>,
+    /// Resources keyed by URI
+    pub resources: Arc<TokioMutex<HashMap<String, ResourceEntry>>>,
+    /// Session environment merged into every spawned command
+    pub env: Arc<TokioMutex<HashMap<String, String>>>,
+    /// Environment snapshots in creation order, keyed by token
+    pub snapshots: Arc<TokioMutex<Vec<(String, EnvSnapshot)>>>,
+    /// Where job records are persisted (NU_STATE_FILE)
+    pub state_file: Option<PathBuf>,
+    /// Still-running jobs started by a previous server run; only signalling is possible
+    pub orphans: Arc<TokioMutex<HashMap<String, PersistedProcess>>>,
+    /// Staged nu.apply edits in creation order, keyed by token
+    pub staged_applies: Arc<TokioMutex<Vec<(String, StagedApply)>>>,
 }
 
 impl AppState {
     pub fn new() -> Self {
-        let initial_cwd = std::env::current_dir()
-            .unwrap_or_else(|_| PathBuf::from("."))
+        let root = std::env::var("NU_ROOT").ok().filter(|r| !r.is_empty()).map(|root| {
+            std::fs::canonicalize(&root).unwrap_or_else(|e| {
+                tracing::error!("NU_ROOT {} is not accessible ({}); commands will start there anyway", root, e);
+                PathBuf::from(root)
+            })
+        });
+        let initial_cwd = root
+            .clone()
+            .unwrap_or_else(|| std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")))
             .to_string_lossy()
             .to_string();
+        let state_file = std::env::var("NU_STATE_FILE").ok().filter(|p| !p.is_empty()).map(PathBuf::from);
         Self {
             processes: Arc::new(TokioMutex::new(HashMap::new())),
-            cwd: Arc::new(TokioMutex::new(initial_cwd)),
+            cwds: Arc::new(TokioMutex::new(HashMap::new())),
+            initial_cwd,
+            root,
+            dir_stacks: Arc::new(TokioMutex::new(HashMap::new())),
+            resources: Arc::new(TokioMutex::new(HashMap::new())),
+            env: Arc::new(TokioMutex::new(HashMap::new())),
+            snapshots: Arc::new(TokioMutex::new(Vec::new())),
+            orphans: Arc::new(TokioMutex::new(
+                state_file.as_deref().map(load_orphans).unwrap_or_default(),
+            )),
+            state_file,
+            staged_applies: Arc::new(TokioMutex::new(Vec::new())),
+        }
+    }
+
+    /// Get a session's current working directory
+    pub async fn get_cwd(&self, session: &str) -> String {
+        self.cwds
+            .lock()
+            .await
+            .get(session)
+            .cloned()
+            .unwrap_or_else(|| self.initial_cwd.clone())
+    }
+
+    /// Set a session's working directory to a specific path
+    pub async fn set_cwd(&self, session: &str, path: String) {
+        self.cwds.lock().await.insert(session.to_string(), path);
+    }
+
+    /// Whether a canonical path lies inside NU_ROOT (always true when no root is set)
+    pub fn is_within_root(&self, path: &str) -> bool {
+        self.root.as_ref().is_none_or(|root| std::path::Path::new(path).starts_with(root))
+    }
+
+    /// Push a directory onto a session's stack
+    pub async fn push_dir(&self, session: &str, dir: String) {
+        self.dir_stacks.lock().await.entry(session.to_string()).or_default().push(dir);
+    }
+
+    /// Pop the most recent directory from a session's stack
+    pub async fn pop_dir(&self, session: &str) -> Option<String> {
+        self.dir_stacks.lock().await.get_mut(session)?.pop()
+    }
+
+    /// Depth of a session's directory stack
+    pub async fn dir_stack_depth(&self, session: &str) -> usize {
+        self.dir_stacks.lock().await.get(session).map_or(0, Vec::len)
+    }
+
+    /// Get a copy of the session environment
+    pub async fn get_env(&self) -> HashMap<String, String> {
+        self.env.lock().await.clone()
+    }
+
+    /// Set a session environment variable, returning the previous value
+    pub async fn set_env_var(&self, name: String, value: String) -> Option<String> {
+        self.env.lock().await.insert(name, value)
+    }
+
+    /// Remove a session environment variable, returning its value if it was set
+    pub async fn unset_env_var(&self, name: &str) -> Option<String> {
+        self.env.lock().await.remove(name)
+    }
+
+    /// Capture the default session's CWD and the session environment, returning a restore token
+    pub async fn snapshot_env(&self) -> String {
+        use nanoid::nanoid;
+        let token = format!("snap_{}", nanoid!(6));
+        let snapshot = EnvSnapshot {
+            cwd: self.get_cwd(DEFAULT_SESSION).await,
+            env: self.get_env().await,
+        };
+
+        let mut snapshots = self.snapshots.lock().await;
+        if snapshots.len() >= MAX_SNAPSHOTS {
+            snapshots.remove(0);
         }
+        snapshots.push((token.clone(), snapshot));
+        token
     }
 
-    /// Get current working directory
-    pub async fn get_cwd(&self) -> String {
-        self.cwd.lock().await.clone()
+    /// Restore the default session's CWD and the session environment from a snapshot token
+    pub async fn restore_env(&self, token: &str) -> Option<EnvSnapshot> {
+        let snapshot = self.snapshots
+            .lock()
+            .await
+            .iter()
+            .find(|(t, _)| t == token)
+            .map(|(_, snap)| snap.clone())?;
+
+        self.set_cwd(DEFAULT_SESSION, snapshot.cwd.clone()).await;
+        *self.env.lock().await = snapshot.env.clone();
+        Some(snapshot)
     }
 
-    /// Set working directory to a specific path
-    pub async fn set_cwd(&self, path: String) {
-        *self.cwd.lock().await = path;
+    /// Keep a staged edit and return the token that confirms it
+    pub async fn stage_apply(&self, staged: StagedApply) -> String {
+        use nanoid::nanoid;
+        let token = format!("stage_{}", nanoid!(10));
+        let mut staged_applies = self.staged_applies.lock().await;
+        if staged_applies.len() >= MAX_STAGED_APPLIES {
+            staged_applies.remove(0);
+        }
+        staged_applies.push((token.clone(), staged));
+        token
+    }
+
+    /// Remove and return a staged edit; a token can only be used once
+    pub async fn take_staged_apply(&self, token: &str) -> Option<StagedApply> {
+        let mut staged_applies = self.staged_applies.lock().await;
+        let index = staged_applies.iter().position(|(t, _)| t == token)?;
+        Some(staged_applies.remove(index).1)
     }
 
     /// Generate unique process ID
@@ -111,14 +527,115 @@ impl AppState {
     }
 
     /// Register a new background process
-    pub async fn register_process(&self, id: String, child: Child, command: String) {
-        let info = ProcessInfo::new(child, command);
+    pub async fn register_process(&self, id: String, child: Child, command: String, log_file: Option<PathBuf>) {
+        let info = ProcessInfo { log_file, ..ProcessInfo::new(child, command) };
         self.processes.lock().await.insert(id, info);
+        self.persist_processes().await;
     }
 
     /// Remove process from tracking
     pub async fn remove_process(&self, id: &str) -> Option<ProcessInfo> {
-        self.processes.lock().await.remove(id)
+        let info = self.processes.lock().await.remove(id);
+        if info.is_some() {
+            self.persist_processes().await;
+        }
+        info
+    }
+
+    /// IDs of every tracked job, sorted
+    pub async fn job_ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.processes.lock().await.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    /// IDs of jobs whose status is still `Running`, sorted
+    pub async fn running_job_ids(&self) -> Vec<String> {
+        let statuses: Vec<(String, Arc<TokioMutex<ProcessStatus>>)> = self.processes
+            .lock()
+            .await
+            .iter()
+            .map(|(id, info)| (id.clone(), info.status.clone()))
+            .collect();
+
+        let mut running = Vec::new();
+        for (id, status) in statuses {
+            if *status.lock().await == ProcessStatus::Running {
+                running.push(id);
+            }
+        }
+        running.sort();
+        running
+    }
+
+    /// Drop Completed/Failed jobs that finished more than `ttl` ago, returning their IDs
+    pub async fn reap_finished(&self, ttl: std::time::Duration) -> Vec<String> {
+        let candidates: Vec<(String, Arc<TokioMutex<Option<std::time::Instant>>>)> = self.processes
+            .lock()
+            .await
+            .iter()
+            .map(|(id, info)| (id.clone(), info.completed_at.clone()))
+            .collect();
+
+        let mut expired = Vec::new();
+        for (id, completed_at) in candidates {
+            if completed_at.lock().await.is_some_and(|at| at.elapsed() > ttl) {
+                expired.push(id);
+            }
+        }
+
+        if !expired.is_empty() {
+            let mut processes = self.processes.lock().await;
+            for id in &expired {
+                processes.remove(id);
+            }
+            drop(processes);
+            self.persist_processes().await;
+        }
+        expired
+    }
+
+    /// Remove a job inherited from a previous run
+    pub async fn remove_orphan(&self, id: &str) -> Option<PersistedProcess> {
+        let orphan = self.orphans.lock().await.remove(id);
+        if orphan.is_some() {
+            self.persist_processes().await;
+        }
+        orphan
+    }
+
+    /// Look up a job inherited from a previous run
+    pub async fn get_orphan(&self, id: &str) -> Option<PersistedProcess> {
+        self.orphans.lock().await.get(id).cloned()
+    }
+
+    /// Write current and inherited jobs to NU_STATE_FILE (best-effort)
+    async fn persist_processes(&self) {
+        let Some(ref path) = self.state_file else {
+            return;
+        };
+
+        let now = unix_now();
+        let mut records: Vec<PersistedProcess> = self.orphans.lock().await.values().cloned().collect();
+        records.extend(self.processes.lock().await.iter().filter_map(|(id, info)| {
+            Some(PersistedProcess {
+                id: id.clone(),
+                command: info.command.clone(),
+                pid: info.pid?,
+                started_at: now.saturating_sub(info.started_at.elapsed().as_secs()),
+            })
+        }));
+
+        let json = match serde_json::to_string_pretty(&records) {
+            Ok(json) => json,
+            Err(e) => {
+                tracing::warn!("Failed to serialize job records: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = tokio::fs::write(path, json).await {
+            tracing::warn!("Failed to write state file {}: {}", path.display(), e);
+        }
     }
 
     /// Take the child out for monitoring while keeping ProcessInfo in map
@@ -127,6 +644,11 @@ impl AppState {
         processes.get(id)?.take_child().await
     }
 
+    /// OS process ID of a tracked job
+    pub async fn get_pid(&self, id: &str) -> Option<u32> {
+        self.processes.lock().await.get(id)?.pid
+    }
+
     /// Get process info without removing (for reading status/buffers)
     pub async fn get_process(&self, id: &str) -> Option<ProcessSnapshot> {
         let processes = self.processes.lock().await;
@@ -138,7 +660,9 @@ impl AppState {
         let exit_code_buf = info.exit_code.clone();
         let stdout_buf = info.stdout_buffer.clone();
         let stderr_buf = info.stderr_buffer.clone();
+        let bytes_received = info.bytes_received.load(Ordering::SeqCst);
         let started_at = info.started_at.elapsed().as_secs();
+        let log_file = info.log_file.clone();
         drop(processes); // release lock
 
         // Compute all values first
@@ -155,19 +679,43 @@ impl AppState {
             stdout,
             stderr,
             started_at_secs: started_at,
+            bytes_received,
+            log_file,
         })
     }
 
+    /// Register a resource so it can be listed and read by clients
+    pub async fn register_resource(&self, entry: ResourceEntry) {
+        self.resources.lock().await.insert(entry.uri.clone(), entry);
+    }
+
+    /// Look up a resource by URI
+    pub async fn get_resource(&self, uri: &str) -> Option<ResourceEntry> {
+        self.resources.lock().await.get(uri).cloned()
+    }
+
+    /// List all registered resources
+    pub async fn list_resources(&self) -> Vec<ResourceEntry> {
+        self.resources.lock().await.values().cloned().collect()
+    }
+
     /// Get buffer references directly (for monitor task)
     pub async fn get_buffers(&self, id: &str) -> Option<BufferRefs> {
         let processes = self.processes.lock().await;
         let info = processes.get(id)?;
 
         Some(BufferRefs {
+            child: info.child.clone(),
             stdout: info.stdout_buffer.clone(),
             stderr: info.stderr_buffer.clone(),
             status: info.status.clone(),
             exit_code: info.exit_code.clone(),
+            completed_at: info.completed_at.clone(),
+            bytes_received: info.bytes_received.clone(),
+            output_notify: info.output_notify.clone(),
+            chunks: info.chunks.clone(),
+            detached: info.detached.clone(),
+            detach_notify: info.detach_notify.clone(),
         })
     }
 }
@@ -182,4 +730,112 @@ pub struct ProcessSnapshot {
     pub stdout: String,
     pub stderr: String,
     pub started_at_secs: u64,
+    pub bytes_received: usize,
+    pub log_file: Option<PathBuf>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_bytes_picks_a_unit() {
+        assert_eq!(format_bytes(512), "512B");
+        assert_eq!(format_bytes(3 * 1024 + 512), "3.5KB");
+        assert_eq!(format_bytes(1_258_291), "1.2MB");
+    }
+
+    #[test]
+    fn push_truncated_keeps_the_head_and_counts_dropped_bytes() {
+        let mut buffer = String::new();
+        let mut dropped = 0;
+        for _ in 0..10 {
+            push_truncated(&mut buffer, &"a".repeat(100), 500, &mut dropped);
+        }
+        assert!(buffer.starts_with(&"a".repeat(400)));
+        assert_eq!(dropped, 1000 - 400);
+        assert!(buffer.ends_with(&truncated_marker(dropped)), "{}", buffer);
+        assert_eq!(buffer.matches("<truncated").count(), 1);
+    }
+
+    #[test]
+    fn head_tail_keeps_both_ends_and_counts_dropped_bytes() {
+        let mut buffer = String::new();
+        let mut dropped = 0;
+        let mut total = 0;
+        for i in 0..200 {
+            let line = format!("line {:03}\n", i);
+            total += line.len();
+            push_head_tail(&mut buffer, &line, 1000, 0.3, &mut dropped);
+        }
+
+        assert!(buffer.starts_with("line 000\n"), "{}", buffer);
+        assert!(buffer.ends_with("line 199\n"), "{}", buffer);
+        assert!(buffer.contains(&omitted_marker(dropped)), "{}", buffer);
+        assert_eq!(buffer.matches("bytes omitted").count(), 1);
+        // Every byte is either still in the buffer or counted as dropped
+        assert_eq!(buffer.len() - omitted_marker(dropped).len() + dropped, total);
+        assert!(buffer.len() <= 1000);
+    }
+
+    #[test]
+    fn head_tail_leaves_small_output_alone() {
+        let mut buffer = String::new();
+        let mut dropped = 0;
+        push_head_tail(&mut buffer, "short\n", 1000, 0.3, &mut dropped);
+        assert_eq!(buffer, "short\n");
+        assert_eq!(dropped, 0);
+    }
+
+    #[tokio::test]
+    async fn restore_env_rolls_back_env_and_cwd() {
+        let state = AppState::new();
+        state.set_env_var("STAGE".to_string(), "one".to_string()).await;
+        state.set_cwd(DEFAULT_SESSION, "/tmp".to_string()).await;
+        let token = state.snapshot_env().await;
+
+        state.set_env_var("STAGE".to_string(), "two".to_string()).await;
+        state.set_env_var("EXTRA".to_string(), "x".to_string()).await;
+        state.set_cwd(DEFAULT_SESSION, "/".to_string()).await;
+
+        assert!(state.restore_env(&token).await.is_some());
+        let env = state.get_env().await;
+        assert_eq!(env.get("STAGE").map(String::as_str), Some("one"));
+        assert!(!env.contains_key("EXTRA"));
+        assert_eq!(state.get_cwd(DEFAULT_SESSION).await, "/tmp");
+        assert!(state.restore_env("snap_unknown").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn snapshots_are_bounded() {
+        let state = AppState::new();
+        let first = state.snapshot_env().await;
+        for _ in 0..MAX_SNAPSHOTS {
+            state.snapshot_env().await;
+        }
+        assert_eq!(state.snapshots.lock().await.len(), MAX_SNAPSHOTS);
+        assert!(state.restore_env(&first).await.is_none());
+    }
+
+    #[test]
+    fn chunk_log_sequences_chunks_and_evicts_the_oldest() {
+        let mut log = ChunkLog::default();
+        log.push("stdout", "a".to_string(), false);
+        log.push("stderr", "b".to_string(), false);
+        log.push("eof", String::new(), true);
+
+        let chunks = log.since(0);
+        assert_eq!(chunks.iter().map(|c| c.seq).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert!(chunks.last().unwrap().is_final);
+        assert!(chunks[..2].iter().all(|c| !c.is_final));
+        assert_eq!(log.since(2).len(), 1);
+
+        for _ in 0..MAX_CHUNKS {
+            log.push("stdout", "x".to_string(), false);
+        }
+        let chunks = log.since(0);
+        assert_eq!(chunks.len(), MAX_CHUNKS);
+        // Evicted chunks leave a visible gap: the oldest retained seq is past 1
+        assert_eq!(chunks[0].seq, 4);
+    }
 }